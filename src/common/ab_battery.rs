@@ -25,7 +25,7 @@ impl ABBattery {
         ]
         .into_iter()
     }
-    pub async fn battery_notify(&self) {
+    pub async fn battery_notify(&self, device: &str) {
         let config: crate::data::config::Config =
             crate::data::shared_vars::CONFIG.lock().unwrap().clone();
         for (name, battery) in self.iter() {
@@ -34,24 +34,24 @@ impl ABBattery {
                     ABBatteryState::Low25 if config.notify_on_25_percent => {
                         log::debug!("Battery is low25");
                         crate::common::commands::default_notification(format!(
-                            "{} battery is low - 25%",
-                            name
+                            "{} {} battery is low - 25%",
+                            device, name
                         ))
                         .await;
                     }
                     ABBatteryState::Low10 if config.notify_on_10_percent => {
                         log::debug!("Battery is low10");
                         crate::common::commands::default_notification(format!(
-                            "{} battery is low - 10%",
-                            name
+                            "{} {} battery is low - 10%",
+                            device, name
                         ))
                         .await;
                     }
                     ABBatteryState::Full if config.notify_on_full_charge => {
                         log::debug!("Battery is full");
                         crate::common::commands::default_notification(format!(
-                            "{} battery is full",
-                            name
+                            "{} {} battery is full",
+                            device, name
                         ))
                         .await;
                     }
@@ -1,8 +1,12 @@
+use crate::common::command_queue::{CommandError, CommandQueue, Setting};
+use crate::common::status::DeviceStatus;
+use crate::common::transport::{L2capTransport, Transport};
 use crate::common::{
     ab_battery::{ABBattery, ABBatteryState},
-    ab_state::{Anc, EarCoverState},
+    ab_state::{Anc, ConnectionState, EarCoverState},
 };
-use crate::data::shared_vars::{AB_MONITORS, ADAPTIVE_CAPABLE, BBWATCHING, CONFIG};
+use crate::data::shared_vars::{BBWATCHING, CONFIG, CONNECTED_MODELS, SESSIONS};
+use std::sync::Arc;
 use tokio::sync::oneshot;
 
 #[cfg(target_os = "linux")]
@@ -11,6 +15,7 @@ use ksni::TrayMethods;
 #[derive(Debug, Clone)]
 pub struct ABDevice {
     // apple/beats device
+    pub address: bluer::Address,
     pub model: String,
     pub model_id: u32,
     pub anc_state: Anc,
@@ -18,11 +23,133 @@ pub struct ABDevice {
     pub ear_cover_state: EarCoverState,
     pub last_ear_cover_state: Option<EarCoverState>,
     pub battery_state: ABBattery,
-    pub data_stream: Option<std::sync::Arc<bluer::l2cap::SeqPacket>>,
+    // last value of every 0x09 setting by id, also of unknown ones
+    pub settings: std::collections::BTreeMap<u8, u8>,
+    pub connection_state: ConnectionState,
+    pub data_stream: Option<Arc<dyn Transport>>,
+    pub commands: Option<CommandQueue>,
 }
 
+const HANDSHAKE: [u8; 16] = [
+    0x00, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+// packet to to enshure init data recieved
+const NOTIFICATION_REQUEST: [u8; 10] = [0x04, 0x00, 0x04, 0x00, 0x0f, 0x00, 0xff, 0xff, 0xff, 0xff];
+// request keys to decrypt proximity advertisements
+const KEYS_REQUEST: [u8; 8] = [0x04, 0x00, 0x04, 0x00, 0x30, 0x00, 0x05, 0x00];
+
+fn is_handshake_response(buf: &[u8]) -> bool {
+    buf.first() == Some(&0x01)
+}
+
+// battery, ear or settings data sent after notification request
+fn is_init_data(buf: &[u8]) -> bool {
+    matches!(buf.get(4), Some(0x04 | 0x06 | 0x09))
+}
+
+// sends packets and waits for frame accepted by done, retrying on timeout
+async fn exchange(
+    data_stream: &Arc<dyn Transport>,
+    packets: &[&[u8]],
+    done: fn(&[u8]) -> bool,
+    received: &mut Vec<Vec<u8>>,
+) -> Option<()> {
+    let (timeout, retries) = {
+        let config = CONFIG.lock().unwrap();
+        (
+            std::time::Duration::from_secs(config.handshake_timeout),
+            config.handshake_retries,
+        )
+    };
+    let mut buf = vec![0u8; data_stream.mtu().into()];
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            log::debug!("No response, retrying ({}/{})", attempt, retries);
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut sent = true;
+        for packet in packets {
+            // channel may not be usable right after connecting
+            if let Err(e) = data_stream.send(packet).await {
+                log::debug!("Failed to send packet: {}", e);
+                sent = false;
+                break;
+            }
+        }
+        if !sent {
+            tokio::time::sleep_until(deadline).await;
+            continue;
+        }
+        loop {
+            match tokio::time::timeout_at(deadline, data_stream.recv(&mut buf)).await {
+                Err(_) => break,
+                Ok(Err(e)) => {
+                    log::error!("Failed to receive data: {}", e);
+                    return None;
+                }
+                Ok(Ok(0)) => return None,
+                Ok(Ok(bytes)) => {
+                    let frame = &buf[..bytes];
+                    if !is_handshake_response(frame) {
+                        received.push(frame.to_vec());
+                    }
+                    if done(frame) {
+                        return Some(());
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+pub async fn send_anc(commands: &CommandQueue, anc: Anc) -> Result<(), CommandError> {
+    let value = match anc {
+        Anc::Off => 0x01,
+        Anc::NoiseCancelling => 0x02,
+        Anc::Transparency => 0x03,
+        Anc::Adaptive => 0x04,
+    };
+    commands.send(Setting { id: 0x0d, value }).await
+}
+
+// listening mode change from outside of packet loop, by dbus or cli
+pub async fn set_anc(addr: bluer::Address, anc: Anc) -> Result<(), CommandError> {
+    let status = crate::common::status::get(addr).ok_or(CommandError::NotConnected)?;
+    let supported = match anc {
+        Anc::Adaptive => status.adaptive_capable,
+        _ => status.anc_capable,
+    };
+    if !supported {
+        return Err(CommandError::NotSupported);
+    }
+    let commands = SESSIONS
+        .lock()
+        .unwrap()
+        .get(&addr)
+        .map(|session| session.commands.clone())
+        .ok_or(CommandError::NotConnected)?;
+    send_anc(&commands, anc).await
+}
+
+// how monitoring ended, audio link is only closed after session ended
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MonitorEnd {
+    // AAP channel could not be established
+    GaveUp,
+    Closed,
+    // model from metadata is excluded by allow and deny lists
+    Unmanaged,
+}
+
+#[cfg(target_os = "linux")]
+pub type Gui = ksni::Handle<ABDevice>;
+#[cfg(not(target_os = "linux"))]
+pub type Gui = Dummy;
+
 #[allow(dead_code)]
-struct Dummy;
+#[derive(Clone)]
+pub struct Dummy;
 #[allow(dead_code)]
 impl Dummy {
     fn new() -> Self {
@@ -32,10 +159,29 @@ impl Dummy {
     fn shutdown(&self) {}
 }
 
+// tray and channel of running packet loop
+#[derive(Clone)]
+pub struct Session {
+    pub gui: Option<Gui>,
+    pub data_stream: Arc<dyn Transport>,
+    pub commands: CommandQueue,
+}
+
+impl Session {
+    // packet loop ends once its channel is shut down
+    pub fn shutdown(&self) {
+        if let Some(gui) = &self.gui {
+            gui.shutdown();
+        }
+        let _ = self.data_stream.shutdown();
+    }
+}
+
 impl ABDevice {
     // create new instance of ABDevice with default values
     pub fn new() -> Self {
         Self {
+            address: bluer::Address::any(),
             model: "Unknown".to_string(),
             model_id: 0,
             anc_state: Anc::Off,
@@ -48,57 +194,176 @@ impl ABDevice {
                 right: None,
                 case: None,
             },
+            settings: std::collections::BTreeMap::new(),
+            connection_state: ConnectionState::Disconnected,
             data_stream: None,
+            commands: None,
         }
     }
     pub async fn monitor(
         &mut self,
         pods: bluer::Device,
         adapter: bluer::Adapter,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut disconnect_tx: Option<oneshot::Sender<()>> = None;
-        let (mtu, data_stream) = match Self::connect(pods.clone(), adapter.clone()).await {
-            Some((mtu, data_stream)) => (mtu, data_stream),
-            None => {
+    ) -> Result<MonitorEnd, Box<dyn std::error::Error>> {
+        // tray id and alias depend on address
+        self.address = pods.address();
+        let policy = CONFIG.lock().unwrap().reconnect.policy_for(pods.address());
+        let mut attempt = 0;
+        // keep retrying while audio link is up, events may not come again
+        let (data_stream, init) = loop {
+            if let Some(connection) = self.connect(pods.clone(), adapter.clone()).await {
+                break connection;
+            }
+            if attempt >= policy.max_attempts {
                 log::error!("Failed to establish connection");
-                return Ok(());
+                return Ok(MonitorEnd::GaveUp);
+            }
+            let delay = policy.delay(attempt);
+            attempt += 1;
+            log::warn!(
+                "Failed to establish connection, retrying in {}s ({}/{})",
+                delay.as_secs(),
+                attempt,
+                policy.max_attempts
+            );
+            tokio::time::sleep(delay).await;
+            if !pods.is_connected().await.unwrap_or(false) {
+                log::debug!("Device {} disconnected, giving up", pods.address());
+                return Ok(MonitorEnd::GaveUp);
             }
         };
 
         self.data_stream = Some(data_stream.clone());
 
-        // dummy to have better conditional code handling
-        // won't be triggered in real use
-        #[cfg(not(target_os = "linux"))]
-        let gui = crate::common::ab_device::Dummy::new();
+        let gui = self.spawn_gui().await;
+        self.run(pods.address(), data_stream, gui, init).await
+    }
+
+    // same as monitor, but device is aplin-sim listening on unix socket
+    pub async fn simulate(
+        &mut self,
+        path: &std::path::Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let data_stream: Arc<dyn Transport> =
+            Arc::new(crate::common::transport::UnixTransport::connect(path, 1024).await?);
+        let data_stream = crate::common::capture::wrap(
+            data_stream,
+            bluer::Address::any(),
+            self.model_id,
+            &self.model,
+        );
+        let Some(init) = self.handshake(&data_stream).await else {
+            log::error!("Failed to establish connection with simulator");
+            return Ok(());
+        };
+        self.data_stream = Some(data_stream.clone());
 
-        #[cfg(target_os = "linux")]
-        let gui = self.clone().spawn().await.unwrap();
+        let gui = self.spawn_gui().await;
+
+        self.run(bluer::Address::any(), data_stream, gui, init)
+            .await?;
+        Ok(())
+    }
+
+    // dummy to have better conditional code handling
+    // won't be triggered in real use
+    #[cfg(not(target_os = "linux"))]
+    pub async fn spawn_gui(&self) -> Option<Gui> {
+        Some(Dummy::new())
+    }
+
+    // keep working without tray when there is no StatusNotifier host
+    #[cfg(target_os = "linux")]
+    pub async fn spawn_gui(&self) -> Option<Gui> {
+        match self.clone().spawn().await {
+            Ok(gui) => Some(gui),
+            Err(e) => {
+                log::warn!("Failed to spawn tray, running without it: {}", e);
+                None
+            }
+        }
+    }
+
+    // packet loop, independent of transport data comes from
+    // init holds frames already received during handshake
+    pub async fn run(
+        &mut self,
+        addr: bluer::Address,
+        data_stream: Arc<dyn Transport>,
+        gui: Option<Gui>,
+        init: Vec<Vec<u8>>,
+    ) -> Result<MonitorEnd, Box<dyn std::error::Error>> {
+        let mut disconnect_tx: Option<oneshot::Sender<()>> = None;
+        let mut end = MonitorEnd::Closed;
+        self.address = addr;
+        self.data_stream = Some(data_stream.clone());
+        // queue worker ends with this loop as it owns frames sender
+        let (commands, frames) = CommandQueue::spawn(data_stream.clone());
+        self.commands = Some(commands.clone());
+        CONNECTED_MODELS.lock().await.insert(addr, self.model_id);
+        SESSIONS.lock().unwrap().insert(
+            addr,
+            Session {
+                gui: gui.clone(),
+                data_stream: data_stream.clone(),
+                commands,
+            },
+        );
+        crate::common::status::publish(addr, DeviceStatus::from(&*self));
+        let mut init = init.into_iter();
 
         loop {
-            let mut buf = vec![0u8; mtu.into()];
-            match data_stream.recv(&mut buf).await {
-                Ok(bytes) => {
-                    let buf = &buf[0..bytes];
-                    if buf.len() < 5 {
+            let received = match init.next() {
+                Some(frame) => Ok(frame),
+                None => {
+                    let mut buf = vec![0u8; data_stream.mtu().into()];
+                    data_stream
+                        .recv(&mut buf)
+                        .await
+                        .map(|bytes| buf[0..bytes].to_vec())
+                }
+            };
+            match received {
+                Ok(buf) => {
+                    let buf = &buf[..];
+                    if buf.is_empty() {
                         //FIXME trggered on data_stream_clone.shutdown(std::net::Shutdown::Both);
                         // used for now to break the loop, replace with tx wrapper
                         break;
                     }
+                    // replayed captures and raw input may hold truncated frames
+                    if buf.len() < 5 {
+                        log::debug!("Skipping short packet: {:02x?}", buf);
+                        continue;
+                    }
+                    if is_init_data(buf) {
+                        self.set_connection_state(ConnectionState::Ready);
+                    }
+                    let _ = frames.send(buf.to_vec());
+                    if let (0x09, Some(id), Some(value)) = (buf[4], buf.get(6), buf.get(7)) {
+                        self.settings.insert(*id, *value);
+                    }
                     match buf[4] {
                         0x04 => {
                             log::debug!("battery data");
-                            for payload_sector_start in (7..7 + buf[6] as usize * 5).step_by(5) {
-                                let charge = if buf[payload_sector_start + 2] <= 100 {
-                                    buf[payload_sector_start + 2]
+                            let count = buf.get(6).copied().unwrap_or(0) as usize;
+                            for payload_sector_start in (7..7 + count * 5).step_by(5) {
+                                let Some(payload) =
+                                    buf.get(payload_sector_start..payload_sector_start + 5)
+                                else {
+                                    log::debug!("Skipping truncated battery data: {:02x?}", buf);
+                                    break;
+                                };
+                                let charge = if payload[2] <= 100 {
+                                    payload[2]
                                 } else {
                                     log::error!(
                                         "Invalid charge value: {}, setting default(0)",
-                                        buf[payload_sector_start + 2]
+                                        payload[2]
                                     );
                                     0
                                 };
-                                let status = match buf[payload_sector_start + 3] {
+                                let status = match payload[3] {
                                     0x01 if charge >= 99 => ABBatteryState::Full, // >= 99 for old batteries that can't reach 100 when in use
                                     0x01 => ABBatteryState::Charging,
                                     0x02 if charge == 10 => ABBatteryState::Low10,
@@ -106,15 +371,12 @@ impl ABDevice {
                                     0x02 => ABBatteryState::Discharging,
                                     0x04 => ABBatteryState::Disconnected,
                                     _ => {
-                                        log::error!(
-                                            "Unknown charging status: {}",
-                                            buf[payload_sector_start + 3]
-                                        );
+                                        log::error!("Unknown charging status: {}", payload[3]);
                                         ABBatteryState::Unknown
                                     }
                                 };
 
-                                match buf[payload_sector_start] {
+                                match payload[0] {
                                     0x01 => {
                                         log::debug!(
                                             "Single state: {:?}. Single charge: {}",
@@ -148,30 +410,32 @@ impl ABDevice {
                                         self.battery_state.case = Some((status, charge));
                                     }
                                     _ => {
-                                        log::error!(
-                                            "Unknown battery type {}",
-                                            buf[payload_sector_start]
-                                        );
+                                        log::error!("Unknown battery type {}", payload[0]);
                                     }
                                 }
                             }
                             let battery_to_pass = self.battery_state;
+                            let name = self.name();
                             tokio::spawn(async move {
-                                battery_to_pass.battery_notify().await;
+                                battery_to_pass.battery_notify(&name).await;
                             });
                         }
                         0x06 => {
+                            let (Some(left_cover), Some(right_cover)) = (buf.get(6), buf.get(7))
+                            else {
+                                log::debug!("Skipping truncated device info: {:02x?}", buf);
+                                continue;
+                            };
                             if let Some(tx) = disconnect_tx.take() {
                                 let _ = tx.send(());
                                 log::debug!("Cancelled pending disconnect task");
                             }
                             log::debug!("Device info data");
-                            self.cover_event(buf[6], buf[7]);
+                            self.cover_event(*left_cover, *right_cover);
                             if self.ear_cover_state == EarCoverState::None {
                                 let (tx, rx) = oneshot::channel();
                                 let data_stream_clone = data_stream.clone();
 
-                                #[cfg(target_os = "linux")]
                                 let gui_clone = gui.clone();
                                 let timeout = CONFIG.lock().unwrap().disconnect_timeout;
                                 tokio::spawn(async move {
@@ -180,8 +444,10 @@ impl ABDevice {
                                     tokio::select! {
                                         _ = tokio::time::sleep(std::time::Duration::from_secs(timeout)) => {
                                             log::debug!("Disconnect timer expired - disconnecting");
-                                            gui_clone.shutdown();
-                                            let _ = data_stream_clone.shutdown(std::net::Shutdown::Both);
+                                            if let Some(gui) = gui_clone {
+                                                gui.shutdown();
+                                            }
+                                            let _ = data_stream_clone.shutdown();
                                         }
                                         _ = rx => {
                                             log::debug!("Disconnect timer cancelled");
@@ -192,11 +458,24 @@ impl ABDevice {
                                 disconnect_tx = Some(tx);
                             }
                         }
-                        0x09 if buf[6] == 0x0d => {
-                            self.anc_event(buf[7]);
+                        0x31 => {
+                            log::debug!("Proximity keys data");
+                            self.keys_event(addr, buf);
+                        }
+                        0x09 if buf.get(6) == Some(&0x0d) => match buf.get(7) {
+                            Some(anc_byte) => self.anc_event(*anc_byte),
+                            None => log::debug!("Skipping truncated settings: {:02x?}", buf),
+                        },
+                        0x1d => {
+                            log::debug!("Metadata");
+                            if !self.metadata_event(addr, buf).await {
+                                log::debug!("Device {} is not managed, ending session", addr);
+                                end = MonitorEnd::Unmanaged;
+                                break;
+                            }
                         }
                         0x09 => {
-                            log::debug!("Unknown settings type: {}", buf[6]);
+                            log::debug!("Unknown settings type: {:?}", buf.get(6));
                             // to check 0x17 0x1f 0x24 0x1b
                         }
                         _ => {
@@ -206,32 +485,77 @@ impl ABDevice {
                 }
                 Err(e) => {
                     log::warn!("Failed to receive data: {}\n Airpods disconnected?", e);
-                    BBWATCHING.lock().await.insert(pods.address(), false);
-                    #[cfg(target_os = "linux")]
-                    gui.shutdown();
+                    BBWATCHING.lock().await.insert(addr, false);
+                    if let Some(gui) = &gui {
+                        gui.shutdown();
+                    }
 
                     break;
                 }
             }
-            #[cfg(target_os = "linux")]
-            gui.update(|ab_device: &mut ABDevice| *ab_device = self.clone())
-                .await;
+            crate::common::status::publish(addr, DeviceStatus::from(&*self));
+            if let Some(gui) = &gui {
+                let ab_device = self.clone();
+                gui.update(move |this: &mut ABDevice| *this = ab_device)
+                    .await;
+            }
+        }
+        CONNECTED_MODELS.lock().await.remove(&addr);
+        SESSIONS.lock().unwrap().remove(&addr);
+        crate::common::status::remove(addr);
+        if let Some(gui) = &gui {
+            gui.shutdown();
         }
+        self.commands = None;
+        self.set_connection_state(ConnectionState::Disconnected);
 
-        Ok(())
+        Ok(end)
     }
-    async fn connect(
+    // AAP channel after handshake, with frames received during it
+    pub async fn connect(
+        &mut self,
         pods: bluer::Device,
         adapter: bluer::Adapter,
-    ) -> Option<(u16, std::sync::Arc<bluer::l2cap::SeqPacket>)> {
-        let socket = bluer::l2cap::Socket::new_seq_packet().unwrap();
-        socket
-            .bind(bluer::l2cap::SocketAddr::new(
-                adapter.address().await.unwrap(),
-                bluer::AddressType::BrEdr,
-                0,
-            ))
-            .unwrap();
+    ) -> Option<(Arc<dyn Transport>, Vec<Vec<u8>>)> {
+        let data_stream = self.open(pods, adapter).await?;
+        match self.handshake(&data_stream).await {
+            Some(init) => Some((data_stream, init)),
+            None => {
+                let _ = data_stream.shutdown();
+                None
+            }
+        }
+    }
+
+    // AAP channel without handshake
+    pub async fn open(
+        &self,
+        pods: bluer::Device,
+        adapter: bluer::Adapter,
+    ) -> Option<Arc<dyn Transport>> {
+        // bind to adapter pods are connected through
+        let adapter_addr = match adapter.address().await {
+            Ok(addr) => addr,
+            Err(e) => {
+                log::error!("Failed to get address of adapter {}: {}", adapter.name(), e);
+                return None;
+            }
+        };
+        let socket = match bluer::l2cap::Socket::new_seq_packet() {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::error!("Failed to create socket: {}", e);
+                return None;
+            }
+        };
+        if let Err(e) = socket.bind(bluer::l2cap::SocketAddr::new(
+            adapter_addr,
+            bluer::AddressType::BrEdr,
+            0,
+        )) {
+            log::error!("Failed to bind socket to {}: {}", adapter.name(), e);
+            return None;
+        }
 
         // TODO: figure out later PSM values
         // wether 129 to 255 for le is needed for any actions
@@ -243,31 +567,109 @@ impl ABDevice {
             Ok(stream) => stream,
             Err(e) => {
                 log::error!("Failed to connect to device: {}", e);
-                BBWATCHING.lock().await.remove(&pods.address());
                 return None;
             }
         };
 
-        let mtu = stream.as_ref().recv_mtu().unwrap();
-        log::debug!("MTU: {}", mtu);
+        let data_stream: Arc<dyn Transport> = match L2capTransport::new(stream) {
+            Ok(transport) => Arc::new(transport),
+            Err(e) => {
+                log::error!("Failed to get MTU: {}", e);
+                return None;
+            }
+        };
+        log::debug!("MTU: {}", data_stream.mtu());
+        let data_stream =
+            crate::common::capture::wrap(data_stream, pods.address(), self.model_id, &self.model);
+        Some(data_stream)
+    }
 
-        let data_stream = std::sync::Arc::new(stream);
-        // TODO: figure out how to wait for the connection to be established instead of timer
-        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-        // handshake
-        data_stream
-            .send(&[
-                0x00, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                0x00, 0x00,
-            ])
-            .await
-            .ok()?;
-        // packet to to enshure init data recieved
-        data_stream
-            .send(&[0x04, 0x00, 0x04, 0x00, 0x0f, 0x00, 0xff, 0xff, 0xff, 0xff])
-            .await
-            .ok()?;
-        Some((mtu, data_stream))
+    fn set_connection_state(&mut self, state: ConnectionState) {
+        if self.connection_state != state {
+            log::debug!(
+                "Connection state: {:?} -> {:?}",
+                self.connection_state,
+                state
+            );
+            self.connection_state = state;
+        }
+    }
+
+    // handshake, then notification and key requests, each step waits for response
+    // returns frames received meanwhile except handshake response
+    pub async fn handshake(&mut self, data_stream: &Arc<dyn Transport>) -> Option<Vec<Vec<u8>>> {
+        let mut received = vec![];
+        self.set_connection_state(ConnectionState::Handshaking);
+        if exchange(
+            data_stream,
+            &[&HANDSHAKE],
+            is_handshake_response,
+            &mut received,
+        )
+        .await
+        .is_none()
+        {
+            log::error!("No handshake response from device");
+            self.set_connection_state(ConnectionState::Disconnected);
+            return None;
+        }
+        self.set_connection_state(ConnectionState::Subscribing);
+        // keys are requested together as there is no need to wait for them
+        if exchange(
+            data_stream,
+            &[&NOTIFICATION_REQUEST, &KEYS_REQUEST],
+            is_init_data,
+            &mut received,
+        )
+        .await
+        .is_none()
+        {
+            log::error!("No init data from device");
+            self.set_connection_state(ConnectionState::Disconnected);
+            return None;
+        }
+        self.set_connection_state(ConnectionState::Ready);
+        Some(received)
+    }
+
+    // 0x31 packet: key count, then type, 0x00, length, 0x00 and key for every key
+    pub fn keys_event(&self, addr: bluer::Address, buf: &[u8]) {
+        let mut irk = None;
+        let mut enc_key = None;
+        let mut offset = 7;
+        for _ in 0..buf.get(6).copied().unwrap_or(0) {
+            let (Some(key_type), Some(len)) = (buf.get(offset), buf.get(offset + 2)) else {
+                break;
+            };
+            let Some(key) = buf
+                .get(offset + 4..offset + 4 + *len as usize)
+                .and_then(|key| <[u8; 16]>::try_from(key).ok())
+            else {
+                log::error!("Invalid proximity key length: {}", len);
+                break;
+            };
+            match key_type {
+                0x01 => irk = Some(key),
+                0x04 => enc_key = Some(key),
+                _ => log::debug!("Unknown proximity key type: {}", key_type),
+            }
+            offset += 4 + *len as usize;
+        }
+        if let (Some(irk), Some(enc_key)) = (irk, enc_key) {
+            // keys are zeroed in captures
+            if irk == [0; 16] && enc_key == [0; 16] {
+                log::debug!("Ignoring redacted proximity keys");
+                return;
+            }
+            crate::data::keys::save(
+                addr,
+                &crate::data::keys::ProximityKeys {
+                    model_id: self.model_id,
+                    irk,
+                    enc_key,
+                },
+            );
+        }
     }
 
     pub fn anc_event(&mut self, anc_byte: u8) {
@@ -300,62 +702,91 @@ impl ABDevice {
         }
     }
 
-    pub async fn send_anc(&self, anc: Option<Anc>) {
+    // resolves once device confirmed new mode
+    pub async fn send_anc(&self, anc: Option<Anc>) -> Result<(), CommandError> {
         log::debug!("Sending Anc state: {:?}", anc);
-        let anc_byte = if let Some(anc) = anc {
-            match anc {
-                Anc::Off => 0x01,
-                Anc::NoiseCancelling => 0x02,
-                Anc::Transparency => 0x03,
-                Anc::Adaptive => 0x04,
-            }
-        } else {
+        let anc = anc.unwrap_or_else(|| {
             log::debug!("Anc state is None, falling back to default");
-            0x03 // TODO: pull default from config
+            Anc::Transparency // TODO: pull default from config
+        });
+        let Some(commands) = &self.commands else {
+            return Err(CommandError::NotConnected);
         };
-        self.data_stream
-            .as_ref()
-            .unwrap()
-            .send(&[
-                0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0D, anc_byte, 0x00, 0x00, 0x00,
-            ])
-            .await
-            .unwrap();
+        send_anc(commands, anc).await
     }
     pub fn adaptive_capable(&self) -> bool {
-        ADAPTIVE_CAPABLE.contains(&self.model_id)
+        crate::data::devices::get(self.model_id).is_some_and(|info| info.adaptive)
     }
-    pub fn is_monitors(&self) -> bool {
-        AB_MONITORS.contains(&self.model_id)
+    pub fn anc_capable(&self) -> bool {
+        crate::data::devices::get(self.model_id).is_some_and(|info| info.anc)
+    }
+    pub fn icon(&self) -> crate::data::devices::DeviceIcon {
+        crate::data::devices::get(self.model_id)
+            .map(|info| info.icon)
+            .unwrap_or_default()
+    }
+    // model number in metadata tells apart devices sharing default name,
+    // returns false once known model is excluded by allow and deny lists
+    async fn metadata_event(&mut self, addr: bluer::Address, buf: &[u8]) -> bool {
+        let Some(model_id) = crate::common::ab_model::from_metadata(buf) else {
+            return true;
+        };
+        if model_id == self.model_id {
+            return true;
+        }
+        log::debug!("Model of {} from metadata: {:#06x}", addr, model_id);
+        self.model_id = model_id;
+        CONNECTED_MODELS.lock().await.insert(addr, model_id);
+        let model = crate::data::devices::get(model_id)
+            .and_then(|info| info.name)
+            .unwrap_or_default();
+        CONFIG.lock().unwrap().managed(addr, &model)
+    }
+    // alias from config, model name otherwise
+    pub fn name(&self) -> String {
+        CONFIG
+            .lock()
+            .unwrap()
+            .alias(self.address)
+            .unwrap_or_else(|| self.model.clone())
+    }
+    fn run_hook(&self) {
+        let hook = CONFIG
+            .lock()
+            .unwrap()
+            .hook(self.address, &self.ear_cover_state);
+        if let Some(command) = hook {
+            let env = vec![
+                ("APLIN_ADDRESS", self.address.to_string()),
+                ("APLIN_NAME", self.name()),
+                ("APLIN_MODEL", self.model.clone()),
+                ("APLIN_MODEL_ID", format!("{:#06x}", self.model_id)),
+            ];
+            tokio::spawn(async move {
+                crate::common::commands::run_system_command(&command, env).await;
+            });
+        }
     }
     pub fn cover_event(&mut self, left_cover: u8, right_cover: u8) {
         match (left_cover == 0, right_cover == 0) {
             (true, true) => {
                 log::debug!("Both ears covered");
-                if self.last_ear_cover_state != Some(EarCoverState::Both) {
+                if self.last_ear_cover_state != Some(EarCoverState::Both) && self.anc_capable() {
                     let self_to_move = self.clone();
                     tokio::spawn(async move {
-                        self_to_move.send_anc(self_to_move.last_anc_state).await;
+                        if let Err(e) = self_to_move.send_anc(self_to_move.last_anc_state).await {
+                            log::error!("Failed to restore Anc state: {}", e);
+                        }
                     });
                 }
                 self.last_ear_cover_state = Some(EarCoverState::Both);
                 self.ear_cover_state = EarCoverState::Both;
-                let cb = CONFIG.lock().unwrap().command_both.clone();
-                if let Some(command) = cb {
-                    tokio::spawn(async move {
-                        crate::common::commands::run_system_command(&command).await;
-                    });
-                }
+                self.run_hook();
             }
             (true, false) | (false, true) => {
                 log::debug!("Single ear cover detected");
                 self.ear_cover_state = EarCoverState::Single;
-                let cb = CONFIG.lock().unwrap().command_single.clone();
-                if let Some(command) = cb {
-                    tokio::spawn(async move {
-                        crate::common::commands::run_system_command(&command).await;
-                    });
-                }
+                self.run_hook();
                 // if self.last_ear_cover_state == Some(EarCoverState::Both) {
                 //     self.last_anc_state = Some(self.anc_state);
                 //     //TODO: trigger commands from config for single ear cover
@@ -374,12 +805,7 @@ impl ABDevice {
                 if self.last_ear_cover_state == Some(EarCoverState::Both) {
                     self.last_anc_state = Some(self.anc_state);
                 }
-                let cb = CONFIG.lock().unwrap().command_none.clone();
-                if let Some(command) = cb {
-                    tokio::spawn(async move {
-                        crate::common::commands::run_system_command(&command).await;
-                    });
-                }
+                self.run_hook();
                 //TODO: trigger commands from config for None ear cover
 
                 // FIXME: state doesn't change if only one ear is covered
@@ -393,3 +819,430 @@ impl ABDevice {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::status::{self, StatusEvent};
+    use crate::common::transport::MemoryTransport;
+    use tokio::sync::broadcast;
+    use tokio::task::JoinHandle;
+
+    // packet loop on host end of in-memory channel, returned end plays device
+    fn start(
+        addr: &str,
+        model_id: u32,
+    ) -> (
+        bluer::Address,
+        MemoryTransport,
+        JoinHandle<MonitorEnd>,
+        broadcast::Receiver<StatusEvent>,
+    ) {
+        {
+            let mut config = CONFIG.lock().unwrap();
+            config.notify_on_anc_change = false;
+            config.notify_on_full_charge = false;
+            config.notify_on_25_percent = false;
+            config.notify_on_10_percent = false;
+        }
+        let addr: bluer::Address = addr.parse().unwrap();
+        let (host, device) = MemoryTransport::pair(1024);
+        // subscribed before loop starts to not miss first status
+        let events = status::subscribe();
+        let run = tokio::spawn(async move {
+            let mut ab_device = ABDevice::new();
+            ab_device.model_id = model_id;
+            ab_device
+                .run(addr, Arc::new(host), None, vec![])
+                .await
+                .unwrap()
+        });
+        (addr, device, run, events)
+    }
+
+    // next status published for addr, None once device is removed
+    async fn next_status(
+        events: &mut broadcast::Receiver<StatusEvent>,
+        addr: bluer::Address,
+    ) -> Option<DeviceStatus> {
+        loop {
+            let (event_addr, status) = events.recv().await.unwrap();
+            if event_addr == addr {
+                return status;
+            }
+        }
+    }
+
+    fn component(level: u8, state: &str) -> status::Component {
+        status::Component {
+            level,
+            state: state.to_string(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn decodes_battery_ear_and_anc() {
+        let (addr, device, run, mut events) = start("02:00:00:00:31:01", 0x2014);
+        assert_eq!(
+            next_status(&mut events, addr).await.unwrap().battery.len(),
+            0
+        );
+
+        // right 25% discharging, left 80% discharging, case 100% charging
+        device
+            .send(&[
+                0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x03, 0x02, 0x01, 0x19, 0x02, 0x01, 0x04, 0x01,
+                0x50, 0x02, 0x01, 0x08, 0x01, 0x64, 0x01, 0x01,
+            ])
+            .await
+            .unwrap();
+        let status = next_status(&mut events, addr).await.unwrap();
+        assert_eq!(status.connection, "ready");
+        assert_eq!(status.battery["left"], component(80, "discharging"));
+        assert_eq!(status.battery["right"], component(25, "discharging"));
+        assert_eq!(status.battery["case"], component(100, "full"));
+        assert!(!status.battery.contains_key("single"));
+
+        // left in ear, right out of ear
+        device
+            .send(&[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x01])
+            .await
+            .unwrap();
+        assert_eq!(next_status(&mut events, addr).await.unwrap().ear, "single");
+
+        device
+            .send(&[
+                0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0d, 0x03, 0x00, 0x00, 0x00,
+            ])
+            .await
+            .unwrap();
+        let status = next_status(&mut events, addr).await.unwrap();
+        assert_eq!(status.listening_mode, "transparency");
+        assert_eq!(status.settings[&0x0d], 0x03);
+
+        device.shutdown().unwrap();
+        run.await.unwrap();
+        assert!(next_status(&mut events, addr).await.is_none());
+        assert!(status::get(addr).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn decodes_corpus() {
+        let (addr, device, run, mut events) = start("02:00:00:00:34:01", 0x2014);
+        next_status(&mut events, addr).await.unwrap();
+
+        let (_, frames) =
+            crate::common::pcapng::read(include_bytes!("../../tests/corpus/airpods.pcapng"))
+                .unwrap();
+        for frame in frames.iter().filter(|frame| {
+            frame.direction == crate::common::pcapng::Direction::Received
+                && frame.cid == crate::common::pcapng::AAP_CID
+        }) {
+            device.send(&frame.data).await.unwrap();
+        }
+        device.shutdown().unwrap();
+        run.await.unwrap();
+
+        let mut last = None;
+        while let Some(status) = next_status(&mut events, addr).await {
+            last = Some(status);
+        }
+        let status = last.unwrap();
+        assert_eq!(status.battery["right"], component(90, "discharging"));
+        assert_eq!(status.battery["left"], component(85, "discharging"));
+        assert_eq!(status.battery["case"], component(40, "charging"));
+        assert_eq!(status.ear, "both");
+        assert_eq!(status.listening_mode, "noise-cancelling");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn model_from_metadata() {
+        // device without modalias and unique name
+        let (addr, device, run, mut events) = start("02:00:00:00:45:01", 0);
+        let status = next_status(&mut events, addr).await.unwrap();
+        assert!(!status.anc_capable);
+
+        let mut packet = vec![0x04, 0x00, 0x04, 0x00, 0x1d, 0x00];
+        packet.extend(b"AirPods Pro\0A2698\0Apple Inc.\0");
+        device.send(&packet).await.unwrap();
+        let status = next_status(&mut events, addr).await.unwrap();
+        assert_eq!(status.model_id, 0x2014);
+        assert!(status.anc_capable && status.adaptive_capable);
+
+        device.shutdown().unwrap();
+        run.await.unwrap();
+    }
+
+    // handshake on host end, returned end plays device
+    fn start_handshake() -> (MemoryTransport, JoinHandle<Option<Vec<Vec<u8>>>>) {
+        let (host, device) = MemoryTransport::pair(1024);
+        let host: Arc<dyn Transport> = Arc::new(host);
+        let handshake = tokio::spawn(async move { ABDevice::new().handshake(&host).await });
+        (device, handshake)
+    }
+
+    async fn expect(device: &MemoryTransport, packets: &[&[u8]]) {
+        let mut buf = [0u8; 64];
+        for packet in packets {
+            let len = device.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], *packet);
+        }
+    }
+
+    const HANDSHAKE_RESPONSE: [u8; 10] =
+        [0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00];
+    const BATTERY: [u8; 12] = [
+        0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x01, 0x04, 0x01, 0x55, 0x02, 0x01,
+    ];
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_retries_unanswered_request() {
+        let (device, handshake) = start_handshake();
+        expect(&device, &[&HANDSHAKE]).await;
+        device.send(&HANDSHAKE_RESPONSE).await.unwrap();
+
+        expect(&device, &[&NOTIFICATION_REQUEST, &KEYS_REQUEST]).await;
+        let first = tokio::time::Instant::now();
+        // requests are sent again once timeout of 2s passed
+        expect(&device, &[&NOTIFICATION_REQUEST, &KEYS_REQUEST]).await;
+        assert!(first.elapsed() >= std::time::Duration::from_secs(2));
+        device.send(&BATTERY).await.unwrap();
+
+        assert_eq!(handshake.await.unwrap(), Some(vec![BATTERY.to_vec()]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_gives_up_after_retries() {
+        let start = tokio::time::Instant::now();
+        let (device, handshake) = start_handshake();
+        // first attempt and 3 retries
+        for _ in 0..4 {
+            expect(&device, &[&HANDSHAKE]).await;
+        }
+        assert_eq!(handshake.await.unwrap(), None);
+        assert!(start.elapsed() >= std::time::Duration::from_secs(8));
+        // nothing sent after giving up
+        assert_eq!(device.recv(&mut [0u8; 64]).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_accepts_late_reply_during_retry() {
+        let (device, handshake) = start_handshake();
+        expect(&device, &[&HANDSHAKE]).await;
+        device.send(&HANDSHAKE_RESPONSE).await.unwrap();
+        expect(&device, &[&NOTIFICATION_REQUEST, &KEYS_REQUEST]).await;
+        expect(&device, &[&NOTIFICATION_REQUEST, &KEYS_REQUEST]).await;
+        // reply to first request arrives while second attempt waits
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        device.send(&BATTERY).await.unwrap();
+
+        assert_eq!(handshake.await.unwrap(), Some(vec![BATTERY.to_vec()]));
+        assert_eq!(device.recv(&mut [0u8; 64]).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stores_keys_as_sent() {
+        // only test touching keys file
+        let state = std::env::temp_dir().join(format!("aplin-keys-{}", std::process::id()));
+        std::env::set_var("XDG_STATE_HOME", &state);
+        let (addr, device, run, mut events) = start("02:00:00:00:29:01", 0x200E);
+        next_status(&mut events, addr).await.unwrap();
+
+        // irk of core spec ah() sample, least significant byte first
+        let irk = [
+            0x9b, 0x7d, 0x39, 0x0a, 0xa6, 0x10, 0x10, 0x34, 0x05, 0xad, 0xc8, 0x57, 0xa3, 0x34,
+            0x02, 0xec,
+        ];
+        let enc_key: [u8; 16] = std::array::from_fn(|i| i as u8);
+        let mut packet = vec![
+            0x04, 0x00, 0x04, 0x00, 0x31, 0x00, 0x02, 0x01, 0x00, 0x10, 0x00,
+        ];
+        packet.extend(irk);
+        packet.extend([0x04, 0x00, 0x10, 0x00]);
+        packet.extend(enc_key);
+        device.send(&packet).await.unwrap();
+        // keys are handled before status of next packet is published
+        device
+            .send(&[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x01])
+            .await
+            .unwrap();
+        next_status(&mut events, addr).await.unwrap();
+
+        let keys = crate::data::keys::load()[&addr];
+        assert_eq!(keys.model_id, 0x200E);
+        assert_eq!(keys.irk, irk);
+        assert_eq!(keys.enc_key, enc_key);
+        assert!(crate::common::ab_proximity::resolve(
+            "70:81:94:0D:FB:AA".parse().unwrap(),
+            &keys.irk
+        ));
+
+        device.shutdown().unwrap();
+        run.await.unwrap();
+        std::fs::remove_dir_all(&state).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ends_session_of_denied_model() {
+        CONFIG.lock().unwrap().deny.push("AirPods 3".to_string());
+        let (addr, device, run, mut events) = start("02:00:00:00:45:02", 0);
+        next_status(&mut events, addr).await.unwrap();
+
+        let mut packet = vec![0x04, 0x00, 0x04, 0x00, 0x1d, 0x00];
+        packet.extend(b"AirPods\0A2564\0Apple Inc.\0");
+        device.send(&packet).await.unwrap();
+        assert_eq!(next_status(&mut events, addr).await, None);
+        assert_eq!(run.await.unwrap(), MonitorEnd::Unmanaged);
+        assert!(!SESSIONS.lock().unwrap().contains_key(&addr));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skips_truncated_packets() {
+        let (addr, device, run, mut events) = start("02:00:00:00:33:01", 0x2014);
+        next_status(&mut events, addr).await.unwrap();
+
+        for packet in [
+            &[0x04, 0x00, 0x04][..],
+            // two components announced, second one cut off
+            &[
+                0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x02, 0x04, 0x01, 0x50, 0x02, 0x01, 0x02, 0x01,
+            ],
+            &[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00],
+            &[0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0d],
+            &[0x04, 0x00, 0x04, 0x00, 0x09],
+        ] {
+            device.send(packet).await.unwrap();
+        }
+        let status = next_status(&mut events, addr).await.unwrap();
+        assert_eq!(status.battery["left"], component(80, "discharging"));
+        assert!(!status.battery.contains_key("right"));
+
+        // loop is still alive after short packets
+        device
+            .send(&[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        assert_eq!(next_status(&mut events, addr).await.unwrap().ear, "both");
+        assert_eq!(status::get(addr).unwrap().listening_mode, "off");
+
+        device.shutdown().unwrap();
+        run.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_anc_waits_for_echo() {
+        let (addr, device, run, mut events) = start("02:00:00:00:31:02", 0x2014);
+        next_status(&mut events, addr).await.unwrap();
+
+        let mut set = tokio::spawn(set_anc(addr, Anc::NoiseCancelling));
+        let mut buf = [0u8; 64];
+        let len = device.recv(&mut buf).await.unwrap();
+        let packet = [
+            0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0d, 0x02, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(&buf[..len], &packet);
+        // unrelated setting is not taken as confirmation
+        device
+            .send(&[
+                0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x1b, 0x02, 0x00, 0x00, 0x00,
+            ])
+            .await
+            .unwrap();
+        // still within first attempt of one second
+        let wait = std::time::Duration::from_millis(500);
+        assert!(tokio::time::timeout(wait, &mut set).await.is_err());
+
+        device.send(&packet).await.unwrap();
+        set.await.unwrap().unwrap();
+        assert_eq!(
+            status::get(addr).unwrap().listening_mode,
+            "noise-cancelling"
+        );
+
+        device.shutdown().unwrap();
+        run.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_anc_fails_without_echo() {
+        let (addr, device, run, mut events) = start("02:00:00:00:31:03", 0x2014);
+        next_status(&mut events, addr).await.unwrap();
+
+        let set = tokio::spawn(set_anc(addr, Anc::Transparency));
+        let mut buf = [0u8; 64];
+        // first attempt and both retries
+        for _ in 0..3 {
+            let len = device.recv(&mut buf).await.unwrap();
+            assert_eq!(buf[..len][4..8], [0x09, 0x00, 0x0d, 0x03]);
+        }
+        assert!(matches!(
+            set.await.unwrap(),
+            Err(CommandError::NotAcknowledged)
+        ));
+        device.shutdown().unwrap();
+        run.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_anc_rejects_unsupported_mode() {
+        // first generation AirPods Pro have no adaptive mode
+        let (addr, device, run, mut events) = start("02:00:00:00:31:06", 0x200E);
+        next_status(&mut events, addr).await.unwrap();
+
+        assert!(matches!(
+            set_anc(addr, Anc::Adaptive).await,
+            Err(CommandError::NotSupported)
+        ));
+        let mut buf = [0u8; 64];
+        let wait = std::time::Duration::from_secs(1);
+        assert!(tokio::time::timeout(wait, device.recv(&mut buf))
+            .await
+            .is_err());
+
+        device.shutdown().unwrap();
+        run.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_after_timeout_without_ears() {
+        let (addr, device, mut run, mut events) = start("02:00:00:00:31:04", 0x2014);
+        next_status(&mut events, addr).await.unwrap();
+        let timeout = CONFIG.lock().unwrap().disconnect_timeout;
+
+        device
+            .send(&[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x01, 0x01])
+            .await
+            .unwrap();
+        assert_eq!(next_status(&mut events, addr).await.unwrap().ear, "none");
+        let early = std::time::Duration::from_secs(timeout - 1);
+        assert!(tokio::time::timeout(early, &mut run).await.is_err());
+        tokio::time::timeout(std::time::Duration::from_secs(2), run)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(status::get(addr).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wearing_cancels_disconnect_timer() {
+        let (addr, device, mut run, mut events) = start("02:00:00:00:31:05", 0x2014);
+        next_status(&mut events, addr).await.unwrap();
+        let timeout = std::time::Duration::from_secs(CONFIG.lock().unwrap().disconnect_timeout);
+
+        device
+            .send(&[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x01, 0x01])
+            .await
+            .unwrap();
+        next_status(&mut events, addr).await.unwrap();
+        tokio::time::sleep(timeout / 2).await;
+        device
+            .send(&[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        assert_eq!(next_status(&mut events, addr).await.unwrap().ear, "both");
+        assert!(tokio::time::timeout(timeout * 2, &mut run).await.is_err());
+
+        device.shutdown().unwrap();
+        run.await.unwrap();
+    }
+}
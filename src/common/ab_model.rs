@@ -0,0 +1,113 @@
+use crate::common::ab_proximity::ProximityData;
+use crate::data::shared_vars::{AB_DEVICES, AB_NAMES};
+
+// AAP service, also present on pods whose modalias is missing
+const AAP_UUID: bluer::Uuid = bluer::Uuid::from_u128(0x74ec2172_0bad_4d01_8f77_997b2be0722a);
+const APPLE_VENDOR_ID: u32 = 76;
+
+// longest known name contained in device name, "John's AirPods Pro" is AirPods Pro
+fn from_name(name: &str) -> Option<u32> {
+    let name = name.to_lowercase();
+    let custom: Vec<(String, u32)> = crate::data::shared_vars::DEVICES
+        .lock()
+        .unwrap()
+        .iter()
+        // built-in names are model names, not names devices advertise
+        .filter(|(id, _)| !AB_DEVICES.iter().any(|(builtin, _)| builtin == *id))
+        .filter_map(|(id, info)| info.name.clone().map(|name| (name, *id)))
+        .collect();
+    AB_NAMES
+        .iter()
+        .map(|(pattern, id)| (pattern.to_string(), *id))
+        .chain(custom)
+        .filter(|(pattern, _)| name.contains(&pattern.to_lowercase()))
+        .max_by_key(|(pattern, _)| pattern.len())
+        .map(|(_, id)| id)
+}
+
+// product id of Apple/Beats device, 0 when device speaks AAP but model is only
+// known after metadata packet arrives
+pub async fn detect(device: &bluer::Device) -> Option<u32> {
+    let addr = device.address();
+    match device.modalias().await {
+        Ok(Some(modalias)) => {
+            return (modalias.vendor == APPLE_VENDOR_ID
+                && crate::data::devices::get(modalias.product).is_some())
+            .then_some(modalias.product);
+        }
+        Ok(None) => log::debug!("Modalias of {} is empty, guessing model", addr),
+        Err(e) => log::debug!("Failed to get modalias of {}: {}", addr, e),
+    }
+
+    let uuids = device.uuids().await.ok().flatten().unwrap_or_default();
+    if !uuids.contains(&AAP_UUID) {
+        return None;
+    }
+    // proximity pairing data is merged into device once its irk is known
+    if let Some(data) = device
+        .manufacturer_data()
+        .await
+        .ok()
+        .flatten()
+        .and_then(|data| ProximityData::from_manufacturer_data(&data))
+        .filter(|data| crate::data::devices::get(data.model_id).is_some())
+    {
+        log::debug!(
+            "Model of {} from advertisement: {:#06x}",
+            addr,
+            data.model_id
+        );
+        return Some(data.model_id);
+    }
+    if let Some(id) = device
+        .name()
+        .await
+        .ok()
+        .flatten()
+        .and_then(|name| from_name(&name))
+    {
+        log::debug!("Model of {} from name: {:#06x}", addr, id);
+        return Some(id);
+    }
+    log::debug!("Model of {} is unknown until AAP metadata arrives", addr);
+    Some(0)
+}
+
+// metadata packet holds null separated strings: name, model number, manufacturer, ...
+pub fn from_metadata(buf: &[u8]) -> Option<u32> {
+    buf.get(6..)?
+        .split(|byte| *byte == 0x00)
+        .filter_map(|field| std::str::from_utf8(field).ok())
+        .find(|field| {
+            field.len() == 5
+                && field.starts_with('A')
+                && field[1..].bytes().all(|byte| byte.is_ascii_digit())
+        })
+        .and_then(|model_number| {
+            let id = crate::data::devices::by_model_number(model_number);
+            if id.is_none() {
+                log::debug!("Unknown model number {}", model_number);
+            }
+            id
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_of_several_generations_are_not_guessed() {
+        assert_eq!(from_name("John's AirPods"), None);
+        assert_eq!(from_name("John's AirPods Pro"), None);
+        assert_eq!(from_name("AirPods Max"), None);
+    }
+
+    #[test]
+    fn longest_unique_name_wins() {
+        assert_eq!(from_name("John's Beats Fit Pro"), Some(0x2012));
+        assert_eq!(from_name("Beats Studio Buds+"), Some(0x2016));
+        assert_eq!(from_name("Beats Studio Buds"), Some(0x2011));
+        assert_eq!(from_name("Powerbeats Pro 2"), Some(0x201D));
+    }
+}
@@ -0,0 +1,606 @@
+use crate::common::{
+    ab_battery::{ABBattery, ABBatteryState},
+    ab_device::ABDevice,
+    ab_state::EarCoverState,
+};
+use crate::data::{
+    keys::ProximityKeys,
+    shared_vars::{CONFIG, CONNECTED_MODELS},
+};
+use futures::StreamExt;
+use std::collections::HashMap;
+use tokio::sync::oneshot;
+
+#[cfg(target_os = "linux")]
+use ksni::TrayMethods;
+
+pub const APPLE_COMPANY_ID: u16 = 0x004C;
+// proximity pairing message type
+const PROXIMITY_TYPE: u8 = 0x07;
+// type, length, prefix, model(2), status, pods battery, case battery, lid, color, 0x00
+const PROXIMITY_MIN_LEN: usize = 11;
+// encrypted payload follows unencrypted part
+const PROXIMITY_ENCRYPTED_START: usize = 11;
+
+#[derive(Debug, Clone)]
+pub struct ProximityData {
+    pub model_id: u32,
+    pub battery: ABBattery,
+    pub left_in_ear: bool,
+    pub right_in_ear: bool,
+    pub lid_open: bool,
+    flip: bool,
+    encrypted: Option<[u8; 16]>,
+}
+
+impl ProximityData {
+    // data is manufacturer data value for apple company id
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < PROXIMITY_MIN_LEN || data[0] != PROXIMITY_TYPE {
+            return None;
+        }
+        let model_id = u16::from_le_bytes([data[3], data[4]]) as u32;
+        // when bit is not set data is sent by right pod and left/right values are swapped
+        let flip = data[5] & 0x20 == 0;
+        let in_ear = data[5] & 0x0f;
+        let charging = data[7] >> 4;
+
+        let (left, right) = if flip {
+            (data[6] >> 4, data[6] & 0x0f)
+        } else {
+            (data[6] & 0x0f, data[6] >> 4)
+        };
+        let (left_charging, right_charging) = if flip {
+            (charging & 0b010 != 0, charging & 0b001 != 0)
+        } else {
+            (charging & 0b001 != 0, charging & 0b010 != 0)
+        };
+        let (left_in_ear, right_in_ear) = if flip {
+            (in_ear & 0b1000 != 0, in_ear & 0b0010 != 0)
+        } else {
+            (in_ear & 0b0010 != 0, in_ear & 0b1000 != 0)
+        };
+
+        let monitors = crate::data::devices::get(model_id)
+            .is_some_and(|info| info.icon == crate::data::devices::DeviceIcon::Monitors);
+        let battery = if monitors {
+            ABBattery {
+                single: Some(Self::battery(data[6] >> 4, charging & 0b001 != 0)),
+                left: None,
+                right: None,
+                case: None,
+            }
+        } else {
+            ABBattery {
+                single: None,
+                left: Some(Self::battery(left, left_charging)),
+                right: Some(Self::battery(right, right_charging)),
+                case: Some(Self::battery(data[7] & 0x0f, charging & 0b100 != 0)),
+            }
+        };
+
+        Some(Self {
+            model_id,
+            battery,
+            left_in_ear,
+            right_in_ear,
+            lid_open: (data[8] >> 3) & 0x01 == 0,
+            flip,
+            encrypted: data
+                .get(PROXIMITY_ENCRYPTED_START..PROXIMITY_ENCRYPTED_START + 16)
+                .and_then(|payload| payload.try_into().ok()),
+        })
+    }
+
+    // battery is sent in 10% steps, 0x0f stands for not available
+    fn battery(level: u8, charging: bool) -> (ABBatteryState, u8) {
+        if level > 10 {
+            return (ABBatteryState::Disconnected, 0);
+        }
+        let charge = level * 10;
+        let state = match charging {
+            true if charge == 100 => ABBatteryState::Full,
+            true => ABBatteryState::Charging,
+            false => ABBatteryState::Discharging,
+        };
+        (state, charge)
+    }
+
+    // replace 10% steps with exact values from encrypted payload
+    pub fn decrypt(&mut self, enc_key: &[u8; 16]) {
+        use aes::cipher::{BlockDecrypt, KeyInit};
+
+        let Some(encrypted) = self.encrypted else {
+            return;
+        };
+        let mut block = aes::Block::from(encrypted);
+        aes::Aes128::new(enc_key.into()).decrypt_block(&mut block);
+
+        // 0xff stands for not available, high bit for charging
+        let exact = |byte: u8, current: Option<(ABBatteryState, u8)>| {
+            if byte == 0xff || byte & 0x7f > 100 {
+                return current;
+            }
+            let charge = byte & 0x7f;
+            let state = match byte & 0x80 != 0 {
+                true if charge >= 99 => ABBatteryState::Full,
+                true => ABBatteryState::Charging,
+                false => ABBatteryState::Discharging,
+            };
+            Some((state, charge))
+        };
+        let (left, right) = if self.flip {
+            (block[2], block[1])
+        } else {
+            (block[1], block[2])
+        };
+        if self.battery.single.is_some() {
+            self.battery.single = exact(block[1], self.battery.single);
+        } else {
+            self.battery.left = exact(left, self.battery.left);
+            self.battery.right = exact(right, self.battery.right);
+            self.battery.case = exact(block[3], self.battery.case);
+        }
+    }
+
+    pub fn ear_cover_state(&self) -> EarCoverState {
+        match (self.left_in_ear, self.right_in_ear) {
+            (true, true) => EarCoverState::Both,
+            (true, false) | (false, true) => EarCoverState::Single,
+            (false, false) => EarCoverState::None,
+        }
+    }
+
+    pub fn from_manufacturer_data(data: &HashMap<u16, Vec<u8>>) -> Option<Self> {
+        data.get(&APPLE_COMPANY_ID)
+            .and_then(|payload| Self::parse(payload))
+    }
+}
+
+// check whether resolvable private address was generated from irk
+pub fn resolve(addr: bluer::Address, irk: &[u8; 16]) -> bool {
+    use aes::cipher::{BlockEncrypt, KeyInit};
+
+    // address is stored most significant byte first: prand then hash
+    if addr[0] >> 6 != 0b01 {
+        return false;
+    }
+    // ah(k, r) from core spec works on most significant byte first values
+    let mut key = *irk;
+    key.reverse();
+    let mut block = aes::Block::default();
+    block[13..16].copy_from_slice(&addr[0..3]);
+    aes::Aes128::new(&key.into()).encrypt_block(&mut block);
+    block[13..16] == addr[3..6]
+}
+
+// watch proximity pairing advertisements, show battery of nearby paired pods
+// and connect them when case is opened
+pub async fn watch(adapter: bluer::Adapter) -> bluer::Result<()> {
+    use bluer::monitor::{data_type, Monitor, MonitorEvent, Pattern};
+
+    let manager = adapter.monitor().await?;
+    let mut handle = manager
+        .register(Monitor {
+            patterns: Some(vec![Pattern::new(
+                data_type::MANUFACTURER_SPECIFIC_DATA,
+                0,
+                &[0x4C, 0x00, PROXIMITY_TYPE],
+            )]),
+            ..Default::default()
+        })
+        .await?;
+    log::debug!("Proximity monitor registered");
+
+    let mut tasks: HashMap<bluer::Address, oneshot::Sender<()>> = HashMap::new();
+    while let Some(event) = handle.next().await {
+        match event {
+            MonitorEvent::DeviceFound(id) => {
+                log::debug!("Proximity device found: {}", id.device);
+                let device = match adapter.device(id.device) {
+                    Ok(device) => device,
+                    Err(e) => {
+                        log::error!("Failed to get proximity device {}: {}", id.device, e);
+                        continue;
+                    }
+                };
+                let (tx, rx) = oneshot::channel();
+                if let Some(old_tx) = tasks.insert(id.device, tx) {
+                    let _ = old_tx.send(());
+                }
+                let adapter = adapter.clone();
+                let keys = crate::data::keys::load();
+                tokio::spawn(async move { follow(adapter, device, keys, rx).await });
+            }
+            MonitorEvent::DeviceLost(id) => {
+                log::debug!("Proximity device lost: {}", id.device);
+                if let Some(tx) = tasks.remove(&id.device) {
+                    let _ = tx.send(());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// follow single advertising address until it's lost
+async fn follow(
+    adapter: bluer::Adapter,
+    device: bluer::Device,
+    keys: HashMap<bluer::Address, ProximityKeys>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let addr = device.address();
+    // bluetooth address of our device the advertising address belongs to
+    let owner = keys
+        .iter()
+        .find(|(_, keys)| resolve(addr, &keys.irk))
+        .map(|(owner, keys)| (*owner, *keys));
+    if let Some((owner, _)) = owner {
+        log::debug!("Proximity device {} resolved to {}", addr, owner);
+    }
+    let mut events = match device.events().await {
+        Ok(events) => events,
+        Err(e) => {
+            log::error!("Failed to get events for proximity device {}: {}", addr, e);
+            return;
+        }
+    };
+
+    #[cfg(target_os = "linux")]
+    let mut gui: Option<ksni::Handle<ABDevice>> = None;
+
+    let show_tray = CONFIG.lock().unwrap().proximity;
+    // unknown until first advertisement
+    let mut lid_open: Option<bool> = None;
+    let mut manufacturer_data = device.manufacturer_data().await.ok().flatten();
+    loop {
+        if let Some(mut data) = manufacturer_data
+            .as_ref()
+            .and_then(ProximityData::from_manufacturer_data)
+        {
+            if let Some((_, keys)) = owner {
+                data.decrypt(&keys.enc_key);
+            }
+            log::debug!(
+                "Proximity data from {}: {:?}, lid open: {}",
+                addr,
+                data.battery,
+                data.lid_open
+            );
+            let paired = match owner {
+                Some((owner, _)) => vec![owner],
+                None => paired_with_model(&adapter, data.model_id).await,
+            };
+            // devices skipped by allow and deny lists get no tray or auto connect either
+            let paired: Vec<bluer::Address> = {
+                let config = CONFIG.lock().unwrap();
+                let model = crate::data::devices::get(data.model_id)
+                    .and_then(|info| info.name)
+                    .unwrap_or_default();
+                paired
+                    .into_iter()
+                    .filter(|target| config.managed(*target, &model))
+                    .collect()
+            };
+
+            if lid_opened(lid_open, data.lid_open) {
+                // model alone could as well be pods of someone nearby, only resolved ones are connected
+                match (owner, &paired[..]) {
+                    (Some(_), [target]) => auto_connect(&adapter, &device, *target).await,
+                    (None, _) => log::debug!("Not auto connecting unresolved {}", addr),
+                    _ => {}
+                }
+            }
+            lid_open = Some(data.lid_open);
+
+            let visible = show_tray
+                && !paired.is_empty()
+                && match owner {
+                    Some((owner, _)) => !CONNECTED_MODELS.lock().await.contains_key(&owner),
+                    None => !CONNECTED_MODELS
+                        .lock()
+                        .await
+                        .values()
+                        .any(|model_id| *model_id == data.model_id),
+                };
+
+            #[cfg(target_os = "linux")]
+            match (visible, gui.as_ref()) {
+                (true, Some(gui)) => {
+                    let battery = data.battery;
+                    let ear_cover_state = data.ear_cover_state();
+                    gui.update(move |ab_device: &mut ABDevice| {
+                        ab_device.battery_state = battery;
+                        ab_device.ear_cover_state = ear_cover_state;
+                    })
+                    .await;
+                }
+                (true, None) => {
+                    let mut ab_device = ABDevice::new();
+                    // tray is told apart by address of pods, if it's known
+                    ab_device.address = match paired[..] {
+                        [target] => target,
+                        _ => addr,
+                    };
+                    ab_device.model_id = data.model_id;
+                    ab_device.model = crate::data::devices::get(data.model_id)
+                        .and_then(|info| info.name)
+                        .unwrap_or_else(|| "Nearby device".to_string());
+                    ab_device.battery_state = data.battery;
+                    ab_device.ear_cover_state = data.ear_cover_state();
+                    match ab_device.spawn().await {
+                        Ok(handle) => gui = Some(handle),
+                        Err(e) => log::error!("Failed to spawn proximity tray: {}", e),
+                    }
+                }
+                (false, Some(_)) => {
+                    if let Some(gui) = gui.take() {
+                        gui.shutdown();
+                    }
+                }
+                (false, None) => {}
+            }
+            #[cfg(not(target_os = "linux"))]
+            let _ = visible;
+        }
+
+        manufacturer_data = tokio::select! {
+            _ = &mut stop => break,
+            event = events.next() => match event {
+                Some(bluer::DeviceEvent::PropertyChanged(
+                    bluer::DeviceProperty::ManufacturerData(data),
+                )) => Some(data),
+                Some(_) => continue,
+                None => break,
+            },
+        };
+    }
+
+    log::debug!("Stopped following proximity device {}", addr);
+    #[cfg(target_os = "linux")]
+    if let Some(gui) = gui {
+        gui.shutdown();
+    }
+}
+
+// opening case starts advertising, so first advertisement with open lid
+// counts as opening too, same for new task after address rotation
+fn lid_opened(previous: Option<bool>, lid_open: bool) -> bool {
+    lid_open && previous != Some(true)
+}
+
+// avoid showing pods of other people nearby
+async fn paired_with_model(adapter: &bluer::Adapter, model_id: u32) -> Vec<bluer::Address> {
+    let mut paired = vec![];
+    let Ok(addresses) = adapter.device_addresses().await else {
+        return paired;
+    };
+    for addr in addresses {
+        let Ok(device) = adapter.device(addr) else {
+            continue;
+        };
+        if !device.is_paired().await.unwrap_or(false) {
+            continue;
+        }
+        if let Ok(Some(modalias)) = device.modalias().await {
+            if modalias.vendor == 76 && modalias.product == model_id {
+                paired.push(addr);
+            }
+        }
+    }
+    paired
+}
+
+// connect pods as soon as case is opened so audio is ready before they are in ears
+async fn auto_connect(adapter: &bluer::Adapter, device: &bluer::Device, target: bluer::Address) {
+    let auto_connect = CONFIG.lock().unwrap().auto_connect.clone();
+    if !auto_connect.enabled_for(target) {
+        log::debug!("Auto connect disabled for {}", target);
+        return;
+    }
+    match device.rssi().await {
+        Ok(Some(rssi)) if rssi >= auto_connect.rssi_threshold => {}
+        Ok(rssi) => {
+            log::debug!(
+                "Not auto connecting {}, rssi {:?} is below threshold {}",
+                target,
+                rssi,
+                auto_connect.rssi_threshold
+            );
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to get rssi for {}: {}", device.address(), e);
+            return;
+        }
+    }
+    let target_device = match adapter.device(target) {
+        Ok(target_device) => target_device,
+        Err(e) => {
+            log::error!("Failed to get device {}: {}", target, e);
+            return;
+        }
+    };
+    if target_device.is_connected().await.unwrap_or(false) {
+        return;
+    }
+    log::debug!("Case opened, connecting {}", target);
+    if let Err(e) = target_device.connect().await {
+        log::error!("Failed to auto connect {}: {}", target, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ah() sample data of core spec, values most significant byte first
+    const SPEC_IRK: [u8; 16] = [
+        0xec, 0x02, 0x34, 0xa3, 0x57, 0xc8, 0xad, 0x05, 0x34, 0x10, 0x10, 0xa6, 0x0a, 0x39, 0x7d,
+        0x9b,
+    ];
+
+    // device sends keys least significant byte first
+    fn device_irk() -> [u8; 16] {
+        let mut irk = SPEC_IRK;
+        irk.reverse();
+        irk
+    }
+
+    #[test]
+    fn resolves_spec_address() {
+        // prand 708194, hash 0dfbaa
+        let addr: bluer::Address = "70:81:94:0D:FB:AA".parse().unwrap();
+        assert!(resolve(addr, &device_irk()));
+        assert!(!resolve(
+            "70:81:94:0D:FB:AB".parse().unwrap(),
+            &device_irk()
+        ));
+        assert!(!resolve(addr, &SPEC_IRK));
+        // static random address is never resolvable
+        assert!(!resolve(
+            "F0:81:94:0D:FB:AA".parse().unwrap(),
+            &device_irk()
+        ));
+    }
+
+    #[test]
+    fn decrypts_exact_battery() {
+        // 00 55 e4 2a encrypted with key 000102..0f
+        let mut data = vec![
+            0x07, 0x19, 0x01, 0x0e, 0x20, 0x2b, 0x99, 0x8f, 0x11, 0x00, 0x05,
+        ];
+        data.extend([
+            0xf6, 0x35, 0xd6, 0x56, 0xd2, 0x7f, 0x16, 0x94, 0xa4, 0x03, 0xd6, 0x72, 0xd7, 0x8c,
+            0x86, 0x1f,
+        ]);
+        let mut proximity = ProximityData::parse(&data).unwrap();
+        let key: [u8; 16] = std::array::from_fn(|i| i as u8);
+        proximity.decrypt(&key);
+        assert_eq!(
+            proximity.battery.left,
+            Some((ABBatteryState::Discharging, 85))
+        );
+        assert_eq!(proximity.battery.right, Some((ABBatteryState::Full, 100)));
+        assert_eq!(
+            proximity.battery.case,
+            Some((ABBatteryState::Discharging, 42))
+        );
+    }
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    type Level = Option<(ABBatteryState, u8)>;
+
+    #[test]
+    fn parses_advertisements() {
+        use ABBatteryState::{Charging, Discharging, Disconnected, Full};
+
+        // advertisement, model, left, right, case, single, left in ear, right in ear, lid open
+        #[allow(clippy::type_complexity)]
+        let cases: &[(&str, u32, Level, Level, Level, Level, bool, bool, bool)] = &[
+            // advertisement of AirPods Pro: left pod sends, both in ear, case battery not available
+            (
+                "0719010e202b998f110005",
+                0x200E,
+                Some((Discharging, 90)),
+                Some((Discharging, 90)),
+                Some((Disconnected, 0)),
+                None,
+                true,
+                true,
+                true,
+            ),
+            // same with status bits changed: right pod sends, values swapped, right pod and case charging, lid closed
+            (
+                "0719011420027356090005",
+                0x2014,
+                Some((Discharging, 70)),
+                Some((Charging, 30)),
+                Some((Charging, 60)),
+                None,
+                false,
+                true,
+                false,
+            ),
+            // everything charged in case
+            (
+                "071901142022aa7a010005",
+                0x2014,
+                Some((Full, 100)),
+                Some((Full, 100)),
+                Some((Full, 100)),
+                None,
+                true,
+                false,
+                true,
+            ),
+            // single battery of over-ear model
+            (
+                "0719010a20208000010005",
+                0x200A,
+                None,
+                None,
+                None,
+                Some((Discharging, 80)),
+                false,
+                false,
+                true,
+            ),
+        ];
+        for (data, model_id, left, right, case, single, left_in_ear, right_in_ear, lid_open) in
+            cases
+        {
+            let parsed = ProximityData::parse(&hex(data)).unwrap();
+            assert_eq!(parsed.model_id, *model_id, "{}", data);
+            assert_eq!(parsed.battery.left, *left, "{}", data);
+            assert_eq!(parsed.battery.right, *right, "{}", data);
+            assert_eq!(parsed.battery.case, *case, "{}", data);
+            assert_eq!(parsed.battery.single, *single, "{}", data);
+            assert_eq!(parsed.left_in_ear, *left_in_ear, "{}", data);
+            assert_eq!(parsed.right_in_ear, *right_in_ear, "{}", data);
+            assert_eq!(parsed.lid_open, *lid_open, "{}", data);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_and_other_messages() {
+        let data = hex("0719010e202b998f110005");
+        for len in 0..data.len() {
+            assert!(
+                ProximityData::parse(&data[..len]).is_none(),
+                "{} bytes",
+                len
+            );
+        }
+        // nearby info message
+        assert!(ProximityData::parse(&hex("1005011c1e2b998f110005")).is_none());
+
+        assert!(ProximityData::parse(&data).unwrap().encrypted.is_none());
+        // encrypted part is only taken when complete
+        let mut long = data.clone();
+        long.extend([0xaa; 15]);
+        assert!(ProximityData::parse(&long).unwrap().encrypted.is_none());
+        long.push(0xaa);
+        assert_eq!(
+            ProximityData::parse(&long).unwrap().encrypted,
+            Some([0xaa; 16])
+        );
+    }
+
+    #[test]
+    fn lid_opening() {
+        assert!(lid_opened(None, true));
+        assert!(lid_opened(Some(false), true));
+        assert!(!lid_opened(Some(true), true));
+        assert!(!lid_opened(None, false));
+        assert!(!lid_opened(Some(true), false));
+    }
+}
@@ -5,6 +5,15 @@ pub enum EarCoverState {
     None,
 }
 
+// AAP channel setup, device is ready once init data arrived
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConnectionState {
+    Disconnected,
+    Handshaking,
+    Subscribing,
+    Ready,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum Anc {
     Off,
@@ -0,0 +1,295 @@
+use crate::common::ipc::Event;
+use crate::common::status::DeviceStatus;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+const LOW: u8 = 25;
+const CRITICAL: u8 = 10;
+// waiting for daemon to (re)start
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum Format {
+    Waybar,
+    I3blocks,
+    Polybar,
+}
+
+fn level(status: &DeviceStatus, component: &str) -> Option<u8> {
+    status.battery.get(component).map(|value| value.level)
+}
+
+// lowest level of what is worn, case only matters when nothing else is known
+fn lowest(status: &DeviceStatus) -> Option<u8> {
+    ["single", "left", "right"]
+        .iter()
+        .filter_map(|component| level(status, component))
+        .min()
+        .or_else(|| level(status, "case"))
+}
+
+fn percent(value: Option<u8>) -> String {
+    value.map(|value| format!("{}%", value)).unwrap_or_default()
+}
+
+fn battery(status: &DeviceStatus) -> String {
+    if let Some(single) = level(status, "single") {
+        return format!("{}%", single);
+    }
+    [("L", "left"), ("R", "right"), ("C", "case")]
+        .iter()
+        .filter_map(|(short, component)| {
+            level(status, component).map(|value| format!("{} {}%", short, value))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// placeholders: {name} {model} {address} {left} {right} {case} {single} {battery} {anc} {ear}
+pub fn render(template: &str, status: &DeviceStatus) -> String {
+    [
+        ("{name}", status.name.clone()),
+        ("{model}", status.model.clone()),
+        ("{address}", status.address.clone()),
+        ("{left}", percent(level(status, "left"))),
+        ("{right}", percent(level(status, "right"))),
+        ("{case}", percent(level(status, "case"))),
+        ("{single}", percent(level(status, "single"))),
+        ("{battery}", battery(status)),
+        ("{anc}", status.listening_mode.clone()),
+        ("{ear}", status.ear.clone()),
+    ]
+    .iter()
+    .fold(template.to_string(), |text, (placeholder, value)| {
+        text.replace(placeholder, value)
+    })
+    .trim()
+    .to_string()
+}
+
+fn classes(statuses: &[&DeviceStatus]) -> Vec<String> {
+    let mut classes = vec![];
+    if statuses.is_empty() {
+        classes.push("disconnected".to_string());
+    }
+    let lowest = statuses.iter().filter_map(|status| lowest(status)).min();
+    if lowest.is_some_and(|lowest| lowest <= CRITICAL) {
+        classes.push("critical".to_string());
+    } else if lowest.is_some_and(|lowest| lowest <= LOW) {
+        classes.push("low".to_string());
+    }
+    if statuses
+        .iter()
+        .flat_map(|status| status.battery.values())
+        .any(|value| value.state == "charging")
+    {
+        classes.push("charging".to_string());
+    }
+    for status in statuses {
+        if status.anc_capable && !classes.contains(&status.listening_mode) {
+            classes.push(status.listening_mode.clone());
+        }
+    }
+    classes
+}
+
+fn line(format: Format, template: &str, statuses: &[&DeviceStatus]) -> String {
+    let text = statuses
+        .iter()
+        .map(|status| render(template, status))
+        .collect::<Vec<_>>()
+        .join(" | ");
+    match format {
+        Format::Waybar => {
+            let tooltip = statuses
+                .iter()
+                .map(|status| {
+                    format!(
+                        "{}: {}, {}",
+                        status.name,
+                        battery(status),
+                        status.listening_mode
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            let mut output = serde_json::json!({
+                "text": text,
+                "tooltip": tooltip,
+                "class": classes(statuses),
+            });
+            if let Some(lowest) = statuses.iter().filter_map(|status| lowest(status)).min() {
+                output["percentage"] = lowest.into();
+            }
+            output.to_string()
+        }
+        Format::I3blocks | Format::Polybar => text,
+    }
+}
+
+// follows watch stream, prints new line on every change until stdout is closed
+pub async fn run(format: Format, template: &str, device: Option<String>) {
+    let address = device.map(|device| {
+        match crate::data::shared_vars::CONFIG
+            .lock()
+            .unwrap()
+            .resolve_device(&device)
+        {
+            Some(addr) => addr,
+            None => {
+                log::error!("Unknown device {:?}", device);
+                std::process::exit(1);
+            }
+        }
+    });
+    let mut stdout = std::io::stdout();
+    let mut last = None;
+    loop {
+        let mut statuses: BTreeMap<String, DeviceStatus> = BTreeMap::new();
+        let mut lines = match crate::common::ipc::subscribe().await {
+            Ok(lines) => Some(lines),
+            Err(e) => {
+                log::debug!("{}", e);
+                None
+            }
+        };
+        loop {
+            let shown: Vec<&DeviceStatus> = statuses
+                .values()
+                .filter(|status| {
+                    address.is_none_or(|addr| status.address.parse().ok() == Some(addr))
+                })
+                .collect();
+            let output = line(format, template, &shown);
+            if last.as_ref() != Some(&output) {
+                if writeln!(stdout, "{}", output)
+                    .and_then(|_| stdout.flush())
+                    .is_err()
+                {
+                    return;
+                }
+                last = Some(output);
+            }
+
+            let Some(reader) = lines.as_mut() else {
+                break;
+            };
+            match reader.next_line().await {
+                Ok(Some(line)) => match serde_json::from_str::<Event>(&line) {
+                    Ok(event) => event.change.apply(event.address, &mut statuses),
+                    Err(e) => log::debug!("Invalid event {:?}: {}", line, e),
+                },
+                _ => {
+                    statuses.clear();
+                    lines = None;
+                }
+            }
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::status::Component;
+
+    // component, level, state
+    type Battery<'a> = &'a [(&'a str, u8, &'a str)];
+
+    fn status(battery: Battery) -> DeviceStatus {
+        let mut status = DeviceStatus::sample("02:00:00:00:49:01".parse().unwrap());
+        status.battery = battery
+            .iter()
+            .map(|(component, level, state)| {
+                (
+                    component.to_string(),
+                    Component {
+                        level: *level,
+                        state: state.to_string(),
+                    },
+                )
+            })
+            .collect();
+        status
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let pods = status(&[
+            ("left", 80, "discharging"),
+            ("right", 70, "discharging"),
+            ("case", 40, "charging"),
+        ]);
+        let max = status(&[("single", 55, "discharging")]);
+        let cases: &[(&str, &DeviceStatus, &str)] = &[
+            ("{name} {model}", &pods, "work AirPods Pro 2"),
+            ("{address}", &pods, "02:00:00:00:49:01"),
+            ("{left} {right} {case}", &pods, "80% 70% 40%"),
+            ("{battery}", &pods, "L 80% R 70% C 40%"),
+            ("{anc} {ear}", &pods, "off both"),
+            // missing components leave placeholder empty
+            (" {single} ", &pods, ""),
+            ("{battery}", &max, "55%"),
+            ("{single}|{case}", &max, "55%|"),
+            ("{unknown}", &max, "{unknown}"),
+        ];
+        for (template, status, expected) in cases {
+            assert_eq!(render(template, status), *expected, "{}", template);
+        }
+    }
+
+    #[test]
+    fn classes_follow_lowest_worn_component() {
+        let cases: &[(Battery, &[&str])] = &[
+            (
+                &[("left", 80, "discharging"), ("right", 26, "discharging")],
+                &["off"],
+            ),
+            (
+                &[("left", 80, "discharging"), ("right", 25, "discharging")],
+                &["low", "off"],
+            ),
+            (
+                &[("left", 10, "discharging"), ("right", 25, "discharging")],
+                &["critical", "off"],
+            ),
+            // low case does not matter while pods are known
+            (
+                &[("left", 80, "discharging"), ("case", 5, "charging")],
+                &["charging", "off"],
+            ),
+            (&[("case", 5, "discharging")], &["critical", "off"]),
+        ];
+        for (battery, expected) in cases {
+            let status = status(battery);
+            assert_eq!(classes(&[&status]), *expected, "{:?}", battery);
+        }
+        assert_eq!(classes(&[]), ["disconnected"]);
+
+        let mut status = status(&[("single", 90, "discharging")]);
+        status.anc_capable = false;
+        assert!(classes(&[&status]).is_empty());
+    }
+
+    #[test]
+    fn waybar_percentage_is_lowest_bud() {
+        let first = status(&[
+            ("left", 60, "discharging"),
+            ("right", 45, "discharging"),
+            ("case", 5, "discharging"),
+        ]);
+        let second = status(&[("single", 50, "discharging")]);
+        let output: serde_json::Value =
+            serde_json::from_str(&line(Format::Waybar, "{battery}", &[&first, &second])).unwrap();
+        assert_eq!(output["percentage"], 45);
+        assert_eq!(output["text"], "L 60% R 45% C 5% | 50%");
+        assert_eq!(output["class"], serde_json::json!(["off"]));
+
+        let output: serde_json::Value =
+            serde_json::from_str(&line(Format::Waybar, "{battery}", &[])).unwrap();
+        assert!(output.get("percentage").is_none());
+        assert_eq!(output["class"], serde_json::json!(["disconnected"]));
+        assert_eq!(line(Format::I3blocks, "{left}", &[&first]), "60%");
+    }
+}
@@ -0,0 +1,376 @@
+use crate::common::pcapng::{self, Direction, Frame, AAP_CID, AAP_PSM, SIGNALING_CID};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+
+const MAGIC: &[u8] = b"btsnoop\0";
+// hci uart (h4) logs from android, btmon monitor logs
+const DATALINK_H4: u32 = 1002;
+const DATALINK_MONITOR: u32 = 2001;
+// microseconds between btsnoop epoch (year 0) and unix epoch
+const EPOCH_OFFSET: u64 = 0x00dcddb30f2f8000;
+
+const H4_ACL: u8 = 0x02;
+const H4_EVENT: u8 = 0x04;
+const MONITOR_EVENT: u16 = 3;
+const MONITOR_ACL_TX: u16 = 4;
+const MONITOR_ACL_RX: u16 = 5;
+const EVENT_CONNECTION_COMPLETE: u8 = 0x03;
+
+const L2CAP_CONNECTION_REQUEST: u8 = 0x02;
+const L2CAP_CONNECTION_RESPONSE: u8 = 0x03;
+const L2CAP_DISCONNECTION_REQUEST: u8 = 0x06;
+
+enum Packet {
+    Event(Vec<u8>),
+    Acl(Direction, Vec<u8>),
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_be_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+// (controller index, timestamp, packet), other hci packets are skipped
+fn records(data: &[u8]) -> Result<Vec<(u16, u64, Packet)>, String> {
+    if !data.starts_with(MAGIC) {
+        return Err("Not a btsnoop file".to_string());
+    }
+    let datalink = u32_be_at(data, 12).ok_or("Truncated btsnoop header")?;
+    if datalink != DATALINK_H4 && datalink != DATALINK_MONITOR {
+        return Err(format!("Unsupported btsnoop datalink {}", datalink));
+    }
+
+    let mut packets = vec![];
+    let mut offset = 16;
+    while offset < data.len() {
+        // logs of running capture usually end with partially written record
+        let (Some(included), Some(flags), Some(timestamp)) = (
+            u32_be_at(data, offset + 4),
+            u32_be_at(data, offset + 8),
+            data.get(offset + 16..offset + 24),
+        ) else {
+            log::warn!(
+                "Truncated record header at {}, ignoring rest of log",
+                offset
+            );
+            break;
+        };
+        let timestamp = u64::from_be_bytes(timestamp.try_into().unwrap_or_default())
+            .saturating_sub(EPOCH_OFFSET);
+        let Some(record) = data.get(offset + 24..offset + 24 + included as usize) else {
+            log::warn!("Truncated record at {}, ignoring rest of log", offset);
+            break;
+        };
+        offset += 24 + included as usize;
+
+        let (index, packet) = if datalink == DATALINK_H4 {
+            let direction = match flags & 0x01 {
+                0 => Direction::Sent,
+                _ => Direction::Received,
+            };
+            match record.split_first() {
+                Some((&H4_ACL, acl)) => (0, Packet::Acl(direction, acl.to_vec())),
+                Some((&H4_EVENT, event)) => (0, Packet::Event(event.to_vec())),
+                _ => continue,
+            }
+        } else {
+            let index = (flags >> 16) as u16;
+            match flags as u16 {
+                MONITOR_ACL_TX => (index, Packet::Acl(Direction::Sent, record.to_vec())),
+                MONITOR_ACL_RX => (index, Packet::Acl(Direction::Received, record.to_vec())),
+                MONITOR_EVENT => (index, Packet::Event(record.to_vec())),
+                _ => continue,
+            }
+        };
+        packets.push((index, timestamp, packet));
+    }
+    Ok(packets)
+}
+
+// link is (controller index, acl handle)
+type Link = (u16, u16);
+
+#[derive(Default)]
+struct Extractor {
+    addresses: HashMap<Link, bluer::Address>,
+    // partial l2cap frames per link and direction
+    fragments: HashMap<(Link, Direction), Vec<u8>>,
+    // connection requests for AAP psm waiting for response
+    pending: HashMap<(Link, u8), (Direction, u16)>,
+    // channel ids carrying AAP per link and direction
+    channels: HashSet<(Link, Direction, u16)>,
+    interfaces: HashMap<Link, u32>,
+    frames: Vec<Frame>,
+}
+
+fn opposite(direction: Direction) -> Direction {
+    match direction {
+        Direction::Sent => Direction::Received,
+        Direction::Received => Direction::Sent,
+    }
+}
+
+impl Extractor {
+    fn event(&mut self, index: u16, event: &[u8]) {
+        // classic connection complete: status, handle, address
+        if event.first() != Some(&EVENT_CONNECTION_COMPLETE) || event.get(2) != Some(&0x00) {
+            return;
+        }
+        let (Some(handle), Some(addr)) = (u16_at(event, 3), event.get(5..11)) else {
+            return;
+        };
+        let mut addr: [u8; 6] = addr.try_into().unwrap_or_default();
+        addr.reverse();
+        self.addresses
+            .insert((index, handle & 0x0fff), bluer::Address(addr));
+    }
+
+    fn acl(&mut self, index: u16, timestamp: u64, direction: Direction, acl: &[u8]) {
+        let (Some(header), Some(data)) = (u16_at(acl, 0), acl.get(4..)) else {
+            return;
+        };
+        let link = (index, header & 0x0fff);
+        let fragment = self.fragments.entry((link, direction)).or_default();
+        // packet boundary 0b01 continues previous frame, everything else starts new one
+        if (header >> 12) & 0x03 != 0x01 {
+            fragment.clear();
+        }
+        fragment.extend(data);
+
+        let Some(len) = u16_at(fragment, 0) else {
+            return;
+        };
+        if fragment.len() < len as usize + 4 {
+            return;
+        }
+        let frame = std::mem::take(fragment);
+        let cid = u16_at(&frame, 2).unwrap_or(0);
+        let payload = &frame[4..4 + len as usize];
+
+        if cid == SIGNALING_CID {
+            self.signaling(link, direction, payload);
+        } else if self.channels.contains(&(link, direction, cid)) {
+            let interface = self.interface(link);
+            // channel ids differ per connection, corpus announces AAP psm on fixed one
+            self.frames.push(Frame {
+                interface,
+                timestamp,
+                direction,
+                cid: AAP_CID,
+                data: crate::common::capture::redact(payload),
+            });
+        }
+    }
+
+    // signaling packet can contain several commands: code, id, length, data
+    fn signaling(&mut self, link: Link, direction: Direction, payload: &[u8]) {
+        let mut offset = 0;
+        while let (Some(&code), Some(&id), Some(len)) = (
+            payload.get(offset),
+            payload.get(offset + 1),
+            u16_at(payload, offset + 2),
+        ) {
+            let command = payload
+                .get(offset + 4..offset + 4 + len as usize)
+                .unwrap_or_default();
+            offset += 4 + len as usize;
+            match code {
+                L2CAP_CONNECTION_REQUEST if u16_at(command, 0) == Some(AAP_PSM) => {
+                    if let Some(source) = u16_at(command, 2) {
+                        self.pending.insert((link, id), (direction, source));
+                    }
+                }
+                // responses can be pending first, result 0x0000 is success
+                L2CAP_CONNECTION_RESPONSE if u16_at(command, 4) == Some(0x0000) => {
+                    let Some((requester, source)) = self.pending.remove(&(link, id)) else {
+                        continue;
+                    };
+                    let Some(destination) = u16_at(command, 0) else {
+                        continue;
+                    };
+                    log::debug!(
+                        "AAP channel on handle {:#06x}: {:#06x} <-> {:#06x}",
+                        link.1,
+                        source,
+                        destination
+                    );
+                    self.channels.insert((link, requester, destination));
+                    self.channels.insert((link, opposite(requester), source));
+                }
+                // destination channel is on side receiving the request
+                L2CAP_DISCONNECTION_REQUEST => {
+                    if let (Some(destination), Some(source)) =
+                        (u16_at(command, 0), u16_at(command, 2))
+                    {
+                        self.channels.remove(&(link, direction, destination));
+                        self.channels.remove(&(link, opposite(direction), source));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn interface(&mut self, link: Link) -> u32 {
+        let next = self.interfaces.len() as u32;
+        *self.interfaces.entry(link).or_insert(next)
+    }
+}
+
+// pulls AAP frames out of btsnoop log into pcapng corpus, returns number of frames
+pub fn import(input: &Path, output: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let data = std::fs::read(input)?;
+    convert(
+        &data,
+        std::io::BufWriter::new(std::fs::File::create(output)?),
+    )
+}
+
+fn convert<W: Write>(data: &[u8], out: W) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut extractor = Extractor::default();
+    for (index, timestamp, packet) in records(data)? {
+        match packet {
+            Packet::Event(event) => extractor.event(index, &event),
+            Packet::Acl(direction, acl) => extractor.acl(index, timestamp, direction, &acl),
+        }
+    }
+    if extractor.frames.is_empty() {
+        return Err("No AAP frames found in log".into());
+    }
+
+    let mut links: Vec<(Link, u32)> = extractor.interfaces.clone().into_iter().collect();
+    links.sort_by_key(|(_, interface)| *interface);
+    let mut writer = pcapng::Writer::new(out)?;
+    for (link, interface) in links {
+        let start = extractor
+            .frames
+            .iter()
+            .find(|frame| frame.interface == interface)
+            .map(|frame| frame.timestamp)
+            .unwrap_or(0);
+        let name = match extractor.addresses.get(&link) {
+            Some(addr) => addr.to_string(),
+            None => format!("hci{} handle {:#06x}", link.0, link.1),
+        };
+        // model is not known from log, replay uses generic device
+        writer.add_interface(&name, "0x0000 ", start)?;
+    }
+    for frame in &extractor.frames {
+        writer.write(frame)?;
+    }
+    Ok(extractor.frames.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pods at 11:22:33:44:55:66 with AAP and avdtp channel, battery frame is fragmented
+    const LOG: &[u8] = include_bytes!("../../tests/corpus/airpods.btsnoop");
+    const CORPUS: &[u8] = include_bytes!("../../tests/corpus/airpods.pcapng");
+
+    fn frames(log: &[u8]) -> (Vec<pcapng::Interface>, Vec<Frame>) {
+        let mut out = vec![];
+        convert(log, &mut out).unwrap();
+        pcapng::read(&out).unwrap()
+    }
+
+    #[test]
+    fn extracts_aap_frames_of_both_directions() {
+        let (interfaces, frames) = frames(LOG);
+        assert_eq!(interfaces.len(), 1);
+        assert_eq!(interfaces[0].name.as_deref(), Some("11:22:33:44:55:66"));
+
+        // synthetic channel setup, then AAP frames without avdtp ones
+        let (signaling, aap): (Vec<_>, Vec<_>) =
+            frames.iter().partition(|frame| frame.cid == SIGNALING_CID);
+        assert_eq!(signaling.len(), 2);
+        assert_eq!(u16_at(&signaling[0].data, 4), Some(AAP_PSM));
+        assert_eq!(u16_at(&signaling[0].data, 6), Some(AAP_CID));
+        assert!(aap.iter().all(|frame| frame.cid == AAP_CID));
+        let directions: Vec<_> = aap.iter().map(|frame| frame.direction).collect();
+        assert_eq!(
+            directions,
+            [
+                Direction::Sent,
+                Direction::Received,
+                Direction::Sent,
+                Direction::Received,
+                Direction::Received,
+                Direction::Received,
+            ]
+        );
+        assert_eq!(aap[0].data[..6], [0x00, 0x00, 0x04, 0x00, 0x01, 0x00]);
+        // reassembled from two acl packets
+        assert_eq!(aap[3].data.len(), 22);
+        assert_eq!(aap[3].data[4], 0x04);
+        assert_eq!(
+            aap[5].data,
+            [0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0d, 0x02, 0x00, 0x00, 0x00]
+        );
+        assert!(aap[0].timestamp < aap[5].timestamp);
+    }
+
+    #[test]
+    fn keeps_frames_before_truncated_record() {
+        let (_, full) = frames(LOG);
+        let (_, truncated) = frames(&LOG[..LOG.len() - 3]);
+        assert_eq!(truncated.len(), full.len() - 1);
+        assert_eq!(truncated[..], full[..full.len() - 1]);
+        // cut inside record header
+        let (_, truncated) = frames(&LOG[..LOG.len() - 30]);
+        assert_eq!(truncated.len(), full.len() - 1);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(convert(b"not a log", vec![]).is_err());
+        // header only
+        assert!(convert(&LOG[..16], vec![]).is_err());
+    }
+
+    #[test]
+    fn corpus_is_up_to_date() {
+        let mut out = vec![];
+        convert(LOG, &mut out).unwrap();
+        assert!(out == CORPUS, "regenerate with aplin import-btsnoop");
+    }
+
+    #[test]
+    fn redacts_keys() {
+        // last record is received AAP frame, append keys frame on same channel
+        let mut offset = 16;
+        let mut last = offset;
+        while let Some(included) = u32_be_at(LOG, offset + 4) {
+            last = offset;
+            offset += 24 + included as usize;
+        }
+        let mut keys = vec![0x04, 0x00, 0x04, 0x00, 0x31, 0x00, 0x02];
+        keys.extend([0x01, 0x00, 0x10, 0x00]);
+        keys.extend([0xaa; 16]);
+        keys.extend([0x04, 0x00, 0x10, 0x00]);
+        keys.extend([0xbb; 16]);
+        let mut record = LOG[last..last + 24 + 5].to_vec();
+        record[0..8].copy_from_slice(&(1 + 4 + 4 + keys.len() as u32).to_be_bytes().repeat(2));
+        record[24 + 3..24 + 5].copy_from_slice(&(4 + keys.len() as u16).to_le_bytes());
+        record.extend((keys.len() as u16).to_le_bytes());
+        record.extend(&LOG[last + 24 + 7..last + 24 + 9]);
+        record.extend(&keys);
+        let log = [LOG, &record].concat();
+
+        let (_, frames) = frames(&log);
+        let frame = frames.last().unwrap();
+        assert_eq!(frame.data[..11], keys[..11]);
+        assert_eq!(frame.data[11..27], [0; 16]);
+        assert_eq!(frame.data[27..31], keys[27..31]);
+        assert_eq!(frame.data[31..], [0; 16]);
+    }
+}
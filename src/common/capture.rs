@@ -0,0 +1,196 @@
+use crate::common::{
+    ab_device::ABDevice,
+    pcapng::{self, Direction, Frame, AAP_CID, SIGNALING_CID},
+    transport::{MemoryTransport, Transport},
+};
+use crate::data::shared_vars::CAPTURE;
+use futures::future::BoxFuture;
+use std::path::Path;
+use std::sync::Arc;
+
+pub fn start(path: &Path) -> std::io::Result<()> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    *CAPTURE.lock().unwrap() = Some(pcapng::Writer::new(file)?);
+    log::debug!("Capturing AAP traffic to {:#?}", path);
+    Ok(())
+}
+
+// records every packet passing through inner transport
+#[derive(Debug)]
+pub struct CaptureTransport {
+    inner: Arc<dyn Transport>,
+    interface: u32,
+}
+
+// wrap transport if capture was requested, description keeps model for replay
+pub fn wrap(
+    transport: Arc<dyn Transport>,
+    addr: bluer::Address,
+    model_id: u32,
+    model: &str,
+) -> Arc<dyn Transport> {
+    let mut capture = CAPTURE.lock().unwrap();
+    let Some(writer) = capture.as_mut() else {
+        return transport;
+    };
+    let description = format!("{:#06x} {}", model_id, model);
+    match writer.add_interface(&addr.to_string(), &description, pcapng::now()) {
+        Ok(interface) => Arc::new(CaptureTransport {
+            inner: transport,
+            interface,
+        }),
+        Err(e) => {
+            log::error!("Failed to add capture interface: {}", e);
+            transport
+        }
+    }
+}
+
+// keys of device must not end up in shared captures
+pub(crate) fn redact(data: &[u8]) -> Vec<u8> {
+    let mut data = data.to_vec();
+    if data.get(4) == Some(&0x31) {
+        let mut offset = 7;
+        for _ in 0..data.get(6).copied().unwrap_or(0) {
+            let Some(len) = data.get(offset + 2).copied() else {
+                break;
+            };
+            let start = (offset + 4).min(data.len());
+            let end = (start + len as usize).min(data.len());
+            data[start..end].fill(0);
+            offset += 4 + len as usize;
+        }
+    }
+    data
+}
+
+fn record(interface: u32, direction: Direction, data: &[u8]) {
+    if let Some(writer) = CAPTURE.lock().unwrap().as_mut() {
+        let frame = Frame {
+            interface,
+            timestamp: pcapng::now(),
+            direction,
+            cid: AAP_CID,
+            data: redact(data),
+        };
+        if let Err(e) = writer.write(&frame) {
+            log::error!("Failed to write capture: {}", e);
+        }
+    }
+}
+
+impl Transport for CaptureTransport {
+    fn send<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(async move {
+            let sent = self.inner.send(data).await?;
+            record(self.interface, Direction::Sent, data);
+            Ok(sent)
+        })
+    }
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(async move {
+            let bytes = self.inner.recv(buf).await?;
+            if bytes > 0 {
+                record(self.interface, Direction::Received, &buf[..bytes]);
+            }
+            Ok(bytes)
+        })
+    }
+    fn mtu(&self) -> u16 {
+        self.inner.mtu()
+    }
+    fn shutdown(&self) -> std::io::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+// feed recorded session back through the same packet loop
+pub async fn replay(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let (interfaces, frames) = pcapng::read(&std::fs::read(path)?)?;
+    let mut tasks = vec![];
+    for (id, interface) in interfaces.into_iter().enumerate() {
+        let frames: Vec<Frame> = frames
+            .iter()
+            .filter(|frame| frame.interface == id as u32 && frame.cid != SIGNALING_CID)
+            .cloned()
+            .collect();
+        if !frames
+            .iter()
+            .any(|frame| frame.direction == Direction::Received)
+        {
+            continue;
+        }
+        let addr = interface
+            .name
+            .as_deref()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(bluer::Address::any());
+        let description = interface.description.unwrap_or_default();
+        let (model_id, model) = description.split_once(' ').unwrap_or((&description, ""));
+        let model_id = u32::from_str_radix(model_id.trim_start_matches("0x"), 16).unwrap_or(0);
+
+        let mut ab_device = ABDevice::new();
+        ab_device.address = addr;
+        ab_device.model_id = model_id;
+        if !model.is_empty() {
+            ab_device.model = model.to_string();
+        }
+        log::debug!(
+            "Replaying {} frames of {} ({:#06x})",
+            frames.len(),
+            addr,
+            model_id
+        );
+        tasks.push(tokio::spawn(async move {
+            replay_device(ab_device, addr, frames).await;
+        }));
+    }
+    if tasks.is_empty() {
+        return Err("No AAP frames found in capture".into());
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+    Ok(())
+}
+
+async fn replay_device(mut ab_device: ABDevice, addr: bluer::Address, frames: Vec<Frame>) {
+    let (host, device) = MemoryTransport::pair(1024);
+    let device = Arc::new(device);
+
+    // received packets are played with recorded timing
+    let player = device.clone();
+    tokio::spawn(async move {
+        let start = tokio::time::Instant::now();
+        let base = frames.first().map(|frame| frame.timestamp).unwrap_or(0);
+        for frame in frames
+            .iter()
+            .filter(|frame| frame.direction == Direction::Received)
+        {
+            let offset = std::time::Duration::from_micros(frame.timestamp.saturating_sub(base));
+            tokio::time::sleep_until(start + offset).await;
+            if player.send(&frame.data).await.is_err() {
+                break;
+            }
+        }
+        log::debug!("Replay of {} finished", addr);
+        let _ = player.shutdown();
+    });
+
+    // packets sent by aplin during replay are only logged
+    let drain = device.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; drain.mtu().into()];
+        while let Ok(bytes) = drain.recv(&mut buf).await {
+            if bytes == 0 {
+                break;
+            }
+            log::debug!("Replay host sent {:02x?}", &buf[..bytes]);
+        }
+    });
+
+    let gui = ab_device.spawn_gui().await;
+    if let Err(e) = ab_device.run(addr, Arc::new(host), gui, vec![]).await {
+        log::error!("Replay of {} failed: {}", addr, e);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::common::ipc::{self, Request, Response};
+use crate::common::status::DeviceStatus;
+
+fn battery(status: &DeviceStatus) -> String {
+    if status.battery.is_empty() {
+        return "unknown".to_string();
+    }
+    ["single", "left", "right", "case"]
+        .iter()
+        .filter_map(|component| {
+            status
+                .battery
+                .get(*component)
+                .map(|value| (component, value))
+        })
+        .map(|(component, value)| match value.state.as_str() {
+            "charging" | "full" => format!("{} {}% ({})", component, value.level, value.state),
+            _ => format!("{} {}%", component, value.level),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+async fn devices(request: Request) -> Result<Vec<DeviceStatus>, Box<dyn std::error::Error>> {
+    match ipc::request(&request).await? {
+        Response::Devices(devices) => Ok(devices),
+        Response::Error(e) => Err(e.into()),
+        response => Err(format!("Unexpected response {:?}", response).into()),
+    }
+}
+
+pub async fn status(device: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let devices = devices(Request::Status { device }).await?;
+    if devices.is_empty() {
+        println!("No device is connected");
+    }
+    for status in devices {
+        println!(
+            "{} ({}), {}",
+            status.name, status.address, status.connection
+        );
+        println!("  model: {} ({:#06x})", status.model, status.model_id);
+        println!("  battery: {}", battery(&status));
+        if status.anc_capable {
+            println!("  listening mode: {}", status.listening_mode);
+        }
+        println!("  in ear: {}", status.ear);
+    }
+    Ok(())
+}
+
+pub async fn battery_levels(device: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let devices = devices(Request::Status { device }).await?;
+    match &devices[..] {
+        [] => println!("No device is connected"),
+        [status] => println!("{}", battery(status)),
+        _ => {
+            for status in &devices {
+                println!("{}: {}", status.name, battery(status));
+            }
+        }
+    }
+    Ok(())
+}
+
+// prints mode that was set, resolves once device confirmed it
+pub async fn anc(request: Request) -> Result<(), Box<dyn std::error::Error>> {
+    match ipc::request(&request).await? {
+        Response::Anc(mode) => {
+            println!("{}", mode);
+            Ok(())
+        }
+        Response::Error(e) => Err(e.into()),
+        response => Err(format!("Unexpected response {:?}", response).into()),
+    }
+}
@@ -0,0 +1,143 @@
+use crate::common::transport::Transport;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+const COMMAND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+const COMMAND_RETRIES: u32 = 2;
+
+#[derive(Debug)]
+pub enum CommandError {
+    NotConnected,
+    NotSupported,
+    Send(std::io::Error),
+    NotAcknowledged,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::NotConnected => write!(f, "device is not connected"),
+            CommandError::NotSupported => write!(f, "device does not support this setting"),
+            CommandError::Send(e) => write!(f, "failed to send command: {}", e),
+            CommandError::NotAcknowledged => write!(
+                f,
+                "device did not confirm change after {} attempts",
+                COMMAND_RETRIES + 1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+// 0x09 setting, device echoes it back once applied
+#[derive(Debug, Copy, Clone)]
+pub struct Setting {
+    pub id: u8,
+    pub value: u8,
+}
+
+impl Setting {
+    fn packet(&self) -> [u8; 11] {
+        [
+            0x04, 0x00, 0x04, 0x00, 0x09, 0x00, self.id, self.value, 0x00, 0x00, 0x00,
+        ]
+    }
+    fn acknowledged_by(&self, frame: &[u8]) -> bool {
+        frame.get(4) == Some(&0x09)
+            && frame.get(6) == Some(&self.id)
+            && frame.get(7) == Some(&self.value)
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    setting: Setting,
+    reply: oneshot::Sender<Result<(), CommandError>>,
+}
+
+// handle to queue of single device, writes are serialized by worker
+#[derive(Debug, Clone)]
+pub struct CommandQueue {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl CommandQueue {
+    // worker stops once returned frame sender is dropped
+    pub fn spawn(data_stream: Arc<dyn Transport>) -> (Self, mpsc::UnboundedSender<Vec<u8>>) {
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        let (frames_tx, frames_rx) = mpsc::unbounded_channel();
+        tokio::spawn(worker(data_stream, requests_rx, frames_rx));
+        (
+            Self {
+                requests: requests_tx,
+            },
+            frames_tx,
+        )
+    }
+
+    pub async fn send(&self, setting: Setting) -> Result<(), CommandError> {
+        let (reply, result) = oneshot::channel();
+        self.requests
+            .send(Request { setting, reply })
+            .map_err(|_| CommandError::NotConnected)?;
+        result.await.map_err(|_| CommandError::NotConnected)?
+    }
+}
+
+async fn worker(
+    data_stream: Arc<dyn Transport>,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+    mut frames: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    loop {
+        // frames received while idle are not interesting
+        let request = tokio::select! {
+            request = requests.recv() => match request {
+                Some(request) => request,
+                None => return,
+            },
+            frame = frames.recv() => match frame {
+                Some(_) => continue,
+                None => return,
+            },
+        };
+        let result = execute(&data_stream, &mut frames, request.setting).await;
+        match &result {
+            Ok(()) => log::debug!("Command {:?} acknowledged", request.setting),
+            Err(e) => log::error!("Command {:?} failed: {}", request.setting, e),
+        }
+        let _ = request.reply.send(result);
+    }
+}
+
+async fn execute(
+    data_stream: &Arc<dyn Transport>,
+    frames: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+    setting: Setting,
+) -> Result<(), CommandError> {
+    for attempt in 0..=COMMAND_RETRIES {
+        if attempt > 0 {
+            log::debug!(
+                "No echo for {:?}, retrying ({}/{})",
+                setting,
+                attempt,
+                COMMAND_RETRIES
+            );
+        }
+        data_stream
+            .send(&setting.packet())
+            .await
+            .map_err(CommandError::Send)?;
+        let deadline = tokio::time::Instant::now() + COMMAND_TIMEOUT;
+        loop {
+            match tokio::time::timeout_at(deadline, frames.recv()).await {
+                Err(_) => break,
+                Ok(None) => return Err(CommandError::NotConnected),
+                Ok(Some(frame)) if setting.acknowledged_by(&frame) => return Ok(()),
+                Ok(Some(_)) => {}
+            }
+        }
+    }
+    Err(CommandError::NotAcknowledged)
+}
@@ -25,10 +25,12 @@ pub async fn default_notification(body: String) {
     .unwrap();
 }
 
-pub async fn run_system_command(command: &str) {
+// env tells shared commands which device triggered them
+pub async fn run_system_command(command: &str, env: Vec<(&str, String)>) {
     if let Err(e) = Command::new("sh")
         .arg("-c")
         .arg(command)
+        .envs(env)
         .stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::null())
@@ -0,0 +1,255 @@
+use crate::data::shared_vars::{BBWATCHING, CONFIG, SESSIONS};
+use bluer::{AdapterEvent, AdapterProperty, DeviceEvent, DeviceProperty, SessionEvent};
+use futures::stream::{AbortHandle, Abortable, SelectAll};
+use futures::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::pin::Pin;
+use tokio::sync::oneshot;
+
+type DeviceEvents = Pin<Box<dyn Stream<Item = (bluer::Address, DeviceEvent)> + Send>>;
+
+fn selected(name: &str) -> bool {
+    let selected = &CONFIG.lock().unwrap().adapters;
+    selected.is_empty() || selected.iter().any(|selected| selected == name)
+}
+
+// adapters selected in config, or all of them
+pub async fn adapters(session: &bluer::Session) -> bluer::Result<Vec<bluer::Adapter>> {
+    session
+        .adapter_names()
+        .await?
+        .into_iter()
+        .filter(|name| selected(name))
+        .map(|name| session.adapter(&name))
+        .collect()
+}
+
+// adapter device is connected through, falls back to first one knowing it
+pub async fn adapter_of(
+    session: &bluer::Session,
+    addr: bluer::Address,
+) -> bluer::Result<Option<bluer::Adapter>> {
+    let mut known = None;
+    for adapter in adapters(session).await? {
+        let Ok(device) = adapter.device(addr) else {
+            continue;
+        };
+        match device.is_connected().await {
+            Ok(true) => return Ok(Some(adapter)),
+            Ok(false) => {
+                known.get_or_insert(adapter);
+            }
+            Err(_) => {}
+        }
+    }
+    Ok(known)
+}
+
+// one event stream per known device, subscribed once
+pub struct DeviceManager {
+    adapter: bluer::Adapter,
+    devices: HashMap<bluer::Address, AbortHandle>,
+    events: SelectAll<DeviceEvents>,
+}
+
+impl DeviceManager {
+    pub fn new(adapter: bluer::Adapter) -> Self {
+        Self {
+            adapter,
+            devices: HashMap::new(),
+            events: SelectAll::new(),
+        }
+    }
+
+    // runs until adapter disappears or stop is sent
+    pub async fn run(mut self, mut stop: oneshot::Receiver<()>) -> bluer::Result<()> {
+        let mut adapter_events = self.adapter.events().await?;
+        for addr in self.adapter.device_addresses().await? {
+            self.add(addr).await;
+        }
+        log::debug!("Watching {} devices", self.devices.len());
+
+        loop {
+            tokio::select! {
+                _ = &mut stop => {
+                    log::debug!("Adapter {} removed", self.adapter.name());
+                    self.teardown();
+                    return Ok(());
+                }
+                event = adapter_events.next() => match event {
+                    Some(AdapterEvent::DeviceAdded(addr)) => self.add(addr).await,
+                    Some(AdapterEvent::DeviceRemoved(addr)) => self.remove(addr),
+                    // rfkill shows up as power off too
+                    Some(AdapterEvent::PropertyChanged(AdapterProperty::Powered(false))) => {
+                        log::warn!("Adapter {} powered off", self.adapter.name());
+                        self.teardown();
+                    }
+                    Some(AdapterEvent::PropertyChanged(AdapterProperty::Powered(true))) => {
+                        log::debug!("Adapter {} powered on", self.adapter.name());
+                        for addr in self.devices.keys() {
+                            connected(&self.adapter, *addr).await;
+                        }
+                    }
+                    Some(AdapterEvent::PropertyChanged(_)) => {}
+                    None => {
+                        log::warn!("Adapter {} event stream ended", self.adapter.name());
+                        self.teardown();
+                        return Ok(());
+                    }
+                },
+                Some((addr, event)) = self.events.next(), if !self.events.is_empty() => {
+                    if let DeviceEvent::PropertyChanged(DeviceProperty::Connected(true)) = event {
+                        log::debug!("Device {} connected", addr);
+                        connected(&self.adapter, addr).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn add(&mut self, addr: bluer::Address) {
+        if self.devices.contains_key(&addr) {
+            return;
+        }
+        let device = match self.adapter.device(addr) {
+            Ok(device) => device,
+            Err(e) => {
+                log::error!("Failed to get device {}: {}", addr, e);
+                return;
+            }
+        };
+        let events = match device.events().await {
+            Ok(events) => events,
+            Err(e) => {
+                log::error!(
+                    "Failed to get events for device {}: {} \n   Device won't be monitored",
+                    addr,
+                    e
+                );
+                return;
+            }
+        };
+        let (abort, registration) = AbortHandle::new_pair();
+        self.events.push(Box::pin(
+            Abortable::new(events, registration).map(move |event| (addr, event)),
+        ));
+        self.devices.insert(addr, abort);
+
+        connected(&self.adapter, addr).await;
+    }
+
+    // ends AAP sessions of this adapter, their packet loops clean up after themselves
+    fn teardown(&self) {
+        let mut sessions = SESSIONS.lock().unwrap();
+        for addr in self.devices.keys() {
+            if let Some(session) = sessions.remove(addr) {
+                log::debug!("Closing session of {}", addr);
+                session.shutdown();
+            }
+        }
+    }
+
+    fn remove(&mut self, addr: bluer::Address) {
+        if let Some(abort) = self.devices.remove(&addr) {
+            log::debug!("Device {} removed", addr);
+            abort.abort();
+        }
+    }
+}
+
+// model is only detected once device is connected, modalias may be missing before
+async fn connected(adapter: &bluer::Adapter, addr: bluer::Address) {
+    let Ok(device) = adapter.device(addr) else {
+        return;
+    };
+    if !device.is_connected().await.unwrap_or(false) {
+        return;
+    }
+    if let Some(true) = BBWATCHING.lock().await.get(&addr) {
+        log::debug!("Device {} is already being watched", addr);
+        return;
+    }
+    let Some(product_id) = crate::common::ab_model::detect(&device).await else {
+        log::debug!("Device {} is not an Apple device", addr);
+        return;
+    };
+    log::debug!(
+        "Device {} ({:?}) is an Apple device, product {:#06x}",
+        addr,
+        device.name().await.ok().flatten(),
+        product_id
+    );
+    let model = crate::data::devices::get(product_id)
+        .and_then(|info| info.name)
+        .unwrap_or_default();
+    if !CONFIG.lock().unwrap().managed(addr, &model) {
+        log::debug!("Device {} is not managed, skipping", addr);
+        return;
+    }
+    // checked again as other adapter may have started it meanwhile
+    if let Some(true) = BBWATCHING.lock().await.insert(addr, true) {
+        return;
+    }
+    crate::common::supervisor::spawn(device, adapter.clone(), product_id);
+}
+
+// device managers and proximity watch follow adapters being added and removed
+pub async fn watch_adapters(session: bluer::Session, proximity: bool) -> bluer::Result<()> {
+    let mut session_events = Box::pin(session.events().await?);
+    let mut managers: HashMap<String, oneshot::Sender<()>> = HashMap::new();
+    // advertisements are the same on every adapter, one is enough
+    let mut proximity_watch: Option<(String, tokio::task::JoinHandle<()>)> = None;
+
+    let mut names = session.adapter_names().await?;
+    if !names.iter().any(|name| selected(name)) {
+        log::warn!("No bluetooth adapter found, waiting for one");
+    }
+    loop {
+        for name in names.drain(..).filter(|name| selected(name)) {
+            if managers.contains_key(&name) {
+                continue;
+            }
+            let adapter = session.adapter(&name)?;
+            log::debug!("Watching adapter {}", name);
+            let (stop_tx, stop_rx) = oneshot::channel();
+            managers.insert(name.clone(), stop_tx);
+            tokio::spawn(async move {
+                if let Err(e) = DeviceManager::new(adapter).run(stop_rx).await {
+                    log::error!("Failed to watch devices on {}: {}", name, e);
+                }
+            });
+        }
+
+        if proximity && proximity_watch.is_none() {
+            if let Some(name) = managers.keys().next() {
+                let adapter = session.adapter(name)?;
+                let task = tokio::spawn(async move {
+                    if let Err(e) = crate::common::ab_proximity::watch(adapter).await {
+                        log::error!("Failed to monitor proximity advertisements: {}", e);
+                    }
+                });
+                proximity_watch = Some((name.clone(), task));
+            }
+        }
+
+        match session_events.next().await {
+            Some(SessionEvent::AdapterAdded(name)) => {
+                log::debug!("Adapter {} added", name);
+                names.push(name);
+            }
+            Some(SessionEvent::AdapterRemoved(name)) => {
+                if let Some(stop) = managers.remove(&name) {
+                    let _ = stop.send(());
+                }
+                if let Some((proximity_name, task)) = proximity_watch.take() {
+                    if proximity_name == name {
+                        task.abort();
+                    } else {
+                        proximity_watch = Some((proximity_name, task));
+                    }
+                }
+            }
+            None => return Ok(()),
+        }
+    }
+}
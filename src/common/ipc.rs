@@ -0,0 +1,298 @@
+use crate::common::ab_state::Anc;
+use crate::common::status::{self, Change, DeviceStatus};
+use crate::data::shared_vars::CONFIG;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+
+// one json object per line in both directions
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Status {
+        device: Option<String>,
+    },
+    SetAnc {
+        device: Option<String>,
+        mode: String,
+    },
+    CycleAnc {
+        device: Option<String>,
+    },
+    Watch,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Response {
+    Devices(Vec<DeviceStatus>),
+    Anc(String),
+    Error(String),
+}
+
+// line of watch stream, timestamp in seconds since unix epoch
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Event {
+    pub timestamp: f64,
+    pub address: String,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+// /tmp is shared by all users, so fallback name carries uid
+pub fn socket_path() -> PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(runtime) => PathBuf::from(runtime).join("aplin.sock"),
+        Err(_) => PathBuf::from(format!("/tmp/aplin-{}.sock", uid())),
+    }
+}
+
+// /proc/self is owned by user running the process
+pub(crate) fn uid() -> u32 {
+    std::fs::metadata("/proc/self")
+        .map(|metadata| metadata.uid())
+        .unwrap_or_else(|e| {
+            log::error!("Failed to get uid: {}", e);
+            0
+        })
+}
+
+// device given by address or alias, otherwise the only connected one
+fn select(device: &Option<String>) -> Result<(bluer::Address, DeviceStatus), String> {
+    if let Some(device) = device {
+        let addr = CONFIG
+            .lock()
+            .unwrap()
+            .resolve_device(device)
+            .ok_or_else(|| format!("Unknown device {}", device))?;
+        return status::get(addr)
+            .map(|status| (addr, status))
+            .ok_or_else(|| format!("Device {} is not connected", device));
+    }
+    let mut statuses = status::all();
+    match statuses.len() {
+        0 => Err("No device is connected".to_string()),
+        1 => Ok(statuses.remove(0)),
+        _ => Err("Several devices are connected, choose one by address or alias".to_string()),
+    }
+}
+
+// modes device supports, in cycling order
+fn modes(status: &DeviceStatus) -> Vec<Anc> {
+    let mut modes = vec![Anc::Off];
+    if status.anc_capable {
+        modes.push(Anc::NoiseCancelling);
+        modes.push(Anc::Transparency);
+    }
+    if status.adaptive_capable {
+        modes.push(Anc::Adaptive);
+    }
+    modes
+}
+
+async fn set_anc(addr: bluer::Address, anc: Anc) -> Response {
+    match crate::common::ab_device::set_anc(addr, anc).await {
+        Ok(()) => Response::Anc(status::anc_id(anc).to_string()),
+        Err(e) => Response::Error(format!("Failed to set {}: {}", status::anc_id(anc), e)),
+    }
+}
+
+async fn handle(request: Request) -> Response {
+    match request {
+        Request::Status { device: None } => Response::Devices(
+            status::all()
+                .into_iter()
+                .map(|(_, status)| status)
+                .collect(),
+        ),
+        Request::Status { device } => match select(&device) {
+            Ok((_, status)) => Response::Devices(vec![status]),
+            Err(e) => Response::Error(e),
+        },
+        Request::SetAnc { device, mode } => {
+            let Some(anc) = status::anc_from_id(&mode) else {
+                return Response::Error(format!(
+                    "Unknown mode {}, expected off, noise-cancelling, transparency or adaptive",
+                    mode
+                ));
+            };
+            match select(&device) {
+                Ok((addr, _)) => set_anc(addr, anc).await,
+                Err(e) => Response::Error(e),
+            }
+        }
+        Request::Watch => Response::Error("Watch is only handled by stream".to_string()),
+        Request::CycleAnc { device } => {
+            let (addr, status) = match select(&device) {
+                Ok(selected) => selected,
+                Err(e) => return Response::Error(e),
+            };
+            let modes = modes(&status);
+            if modes.len() < 2 {
+                return Response::Error("Device does not support listening modes".to_string());
+            }
+            let next = modes
+                .iter()
+                .position(|anc| status::anc_id(*anc) == status.listening_mode)
+                .map(|current| modes[(current + 1) % modes.len()])
+                .unwrap_or(modes[0]);
+            set_anc(addr, next).await
+        }
+    }
+}
+
+async fn send_changes(
+    writer: &mut tokio::net::unix::OwnedWriteHalf,
+    addr: bluer::Address,
+    changes: Vec<Change>,
+) -> std::io::Result<()> {
+    let timestamp = crate::common::pcapng::now() as f64 / 1_000_000.0;
+    for change in changes {
+        let mut line = serde_json::to_string(&Event {
+            timestamp,
+            address: addr.to_string(),
+            change,
+        })?;
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+// connected devices first, then every change until client goes away
+async fn watch(mut writer: tokio::net::unix::OwnedWriteHalf) -> std::io::Result<()> {
+    let mut events = status::subscribe();
+    let mut known: HashMap<bluer::Address, DeviceStatus> = HashMap::new();
+    for (addr, status) in status::all() {
+        send_changes(&mut writer, addr, status::changes(None, Some(&status))).await?;
+        known.insert(addr, status);
+    }
+    loop {
+        let updates = match events.recv().await {
+            Ok(update) => vec![update],
+            // missed some changes, compare with current state instead
+            Err(RecvError::Lagged(_)) => {
+                let current: HashMap<_, _> = status::all().into_iter().collect();
+                let mut updates: Vec<_> = known
+                    .keys()
+                    .filter(|addr| !current.contains_key(addr))
+                    .map(|addr| (*addr, None))
+                    .collect();
+                updates.extend(
+                    current
+                        .into_iter()
+                        .map(|(addr, status)| (addr, Some(status))),
+                );
+                updates
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        for (addr, status) in updates {
+            let changes = status::changes(known.get(&addr), status.as_ref());
+            send_changes(&mut writer, addr, changes).await?;
+            match status {
+                Some(status) => known.insert(addr, status),
+                None => known.remove(&addr),
+            };
+        }
+    }
+}
+
+async fn client(stream: UnixStream) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(Request::Watch) => return watch(writer).await,
+            Ok(request) => handle(request).await,
+            Err(e) => Response::Error(format!("Invalid request: {}", e)),
+        };
+        let mut response = serde_json::to_string(&response)?;
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+// control socket for cli, stale socket of previous run is replaced
+pub async fn serve() -> Result<(), Box<dyn std::error::Error>> {
+    let path = socket_path();
+    if UnixStream::connect(&path).await.is_ok() {
+        return Err(format!("{} is used by another aplin", path.display()).into());
+    }
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    // only owner may change listening mode
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    log::debug!("Listening on {}", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = client(stream).await {
+                log::debug!("Control client failed: {}", e);
+            }
+        });
+    }
+}
+
+// prints events of running daemon as they come, one json object per line
+pub async fn watch_stream() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let mut lines = subscribe().await?;
+    let mut stdout = std::io::stdout();
+    while let Some(line) = lines.next_line().await? {
+        // reader of pipe went away
+        if writeln!(stdout, "{}", line)
+            .and_then(|_| stdout.flush())
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
+    Err("Daemon closed connection".into())
+}
+
+// lines of watch stream
+pub async fn subscribe(
+) -> Result<Lines<BufReader<tokio::net::unix::OwnedReadHalf>>, Box<dyn std::error::Error>> {
+    let path = socket_path();
+    let stream = UnixStream::connect(&path).await.map_err(|e| {
+        format!(
+            "Failed to connect to {}, is aplin running? {}",
+            path.display(),
+            e
+        )
+    })?;
+    let (reader, mut writer) = stream.into_split();
+    let mut request = serde_json::to_string(&Request::Watch)?;
+    request.push('\n');
+    writer.write_all(request.as_bytes()).await?;
+    Ok(BufReader::new(reader).lines())
+}
+
+// single request to running daemon
+pub async fn request(request: &Request) -> Result<Response, Box<dyn std::error::Error>> {
+    let path = socket_path();
+    let stream = UnixStream::connect(&path).await.map_err(|e| {
+        format!(
+            "Failed to connect to {}, is aplin running? {}",
+            path.display(),
+            e
+        )
+    })?;
+    let (reader, mut writer) = stream.into_split();
+    let mut request = serde_json::to_string(request)?;
+    request.push('\n');
+    writer.write_all(request.as_bytes()).await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or("Daemon closed connection")?;
+    Ok(serde_json::from_str(&line)?)
+}
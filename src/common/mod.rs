@@ -1,4 +1,19 @@
 pub mod ab_battery;
 pub mod ab_device;
+pub mod ab_model;
+pub mod ab_proximity;
 pub mod ab_state;
+pub mod bar;
+pub mod btsnoop;
+pub mod capture;
+pub mod cli;
+pub mod command_queue;
 pub mod commands;
+pub mod device_manager;
+pub mod ipc;
+pub mod pcapng;
+pub mod raw;
+pub mod state_file;
+pub mod status;
+pub mod supervisor;
+pub mod transport;
@@ -0,0 +1,248 @@
+use std::io::Write;
+
+// bluetooth h4 packets with 4 byte direction header, understood by wireshark
+pub const LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR: u16 = 201;
+// l2cap channel ids used for captured AAP traffic
+pub const SIGNALING_CID: u16 = 0x0001;
+pub const AAP_CID: u16 = 0x0040;
+pub const AAP_PSM: u16 = 4097;
+
+const SHB_TYPE: u32 = 0x0A0D0D0A;
+const IDB_TYPE: u32 = 0x00000001;
+const EPB_TYPE: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+const OPT_END: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_DESCRIPTION: u16 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Interface {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub linktype: u16,
+}
+
+// l2cap payload of single captured packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub interface: u32,
+    // microseconds since unix epoch
+    pub timestamp: u64,
+    pub direction: Direction,
+    pub cid: u16,
+    pub data: Vec<u8>,
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend(code.to_le_bytes());
+    buf.extend((value.len() as u16).to_le_bytes());
+    buf.extend(value);
+    buf.resize(padded(buf.len()), 0);
+}
+
+pub struct Writer<W: Write> {
+    out: W,
+    interfaces: u32,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut out: W) -> std::io::Result<Self> {
+        let mut body = vec![];
+        body.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend(1u16.to_le_bytes());
+        body.extend(0u16.to_le_bytes());
+        // section length is not known
+        body.extend((-1i64).to_le_bytes());
+        Self::block(&mut out, SHB_TYPE, &body)?;
+        Ok(Self { out, interfaces: 0 })
+    }
+
+    fn block(out: &mut W, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+        let len = (12 + padded(body.len())) as u32;
+        let mut block = Vec::with_capacity(len as usize);
+        block.extend(block_type.to_le_bytes());
+        block.extend(len.to_le_bytes());
+        block.extend(body);
+        block.resize(padded(block.len()), 0);
+        block.extend(len.to_le_bytes());
+        out.write_all(&block)?;
+        out.flush()
+    }
+
+    // every device gets its own interface, returns interface id
+    pub fn add_interface(
+        &mut self,
+        name: &str,
+        description: &str,
+        timestamp: u64,
+    ) -> std::io::Result<u32> {
+        let mut body = vec![];
+        body.extend(LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR.to_le_bytes());
+        body.extend(0u16.to_le_bytes());
+        body.extend(0u32.to_le_bytes());
+        option(&mut body, OPT_IF_NAME, name.as_bytes());
+        option(&mut body, OPT_IF_DESCRIPTION, description.as_bytes());
+        option(&mut body, OPT_END, &[]);
+        Self::block(&mut self.out, IDB_TYPE, &body)?;
+        self.interfaces += 1;
+        let interface = self.interfaces - 1;
+
+        // fake channel setup so wireshark knows AAP channel psm
+        let mut request = vec![0x02, 0x01, 0x04, 0x00];
+        request.extend(AAP_PSM.to_le_bytes());
+        request.extend(AAP_CID.to_le_bytes());
+        let mut response = vec![0x03, 0x01, 0x08, 0x00];
+        response.extend(AAP_CID.to_le_bytes());
+        response.extend(AAP_CID.to_le_bytes());
+        response.extend([0x00, 0x00, 0x00, 0x00]);
+        self.write(&Frame {
+            interface,
+            timestamp,
+            direction: Direction::Sent,
+            cid: SIGNALING_CID,
+            data: request,
+        })?;
+        self.write(&Frame {
+            interface,
+            timestamp,
+            direction: Direction::Received,
+            cid: SIGNALING_CID,
+            data: response,
+        })?;
+        Ok(interface)
+    }
+
+    pub fn write(&mut self, frame: &Frame) -> std::io::Result<()> {
+        // direction, h4 acl type, acl header, l2cap header, payload
+        let mut packet = vec![];
+        packet.extend(match frame.direction {
+            Direction::Sent => 0u32.to_be_bytes(),
+            Direction::Received => 1u32.to_be_bytes(),
+        });
+        packet.push(0x02);
+        // handle per interface, packet boundary flag first automatically flushable
+        packet.extend((((frame.interface + 1) as u16 & 0x0fff) | 0x2000).to_le_bytes());
+        packet.extend((frame.data.len() as u16 + 4).to_le_bytes());
+        packet.extend((frame.data.len() as u16).to_le_bytes());
+        packet.extend(frame.cid.to_le_bytes());
+        packet.extend(&frame.data);
+
+        let mut body = vec![];
+        body.extend(frame.interface.to_le_bytes());
+        body.extend(((frame.timestamp >> 32) as u32).to_le_bytes());
+        body.extend((frame.timestamp as u32).to_le_bytes());
+        body.extend((packet.len() as u32).to_le_bytes());
+        body.extend((packet.len() as u32).to_le_bytes());
+        body.extend(&packet);
+        body.resize(padded(body.len()), 0);
+        Self::block(&mut self.out, EPB_TYPE, &body)
+    }
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+// l2cap frames from little endian pcapng with h4 interfaces, other packets are skipped
+pub fn read(data: &[u8]) -> Result<(Vec<Interface>, Vec<Frame>), String> {
+    let mut interfaces = vec![];
+    let mut frames = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let block_type = u32_at(data, offset).ok_or("Truncated block header")?;
+        let len = u32_at(data, offset + 4).ok_or("Truncated block header")? as usize;
+        if len < 12 || offset + len > data.len() {
+            return Err(format!("Invalid block length {} at {}", len, offset));
+        }
+        let body = &data[offset + 8..offset + len - 4];
+        match block_type {
+            SHB_TYPE => {
+                if u32_at(body, 0) != Some(BYTE_ORDER_MAGIC) {
+                    return Err("Only little endian captures are supported".to_string());
+                }
+                // interface ids are per section
+                interfaces.clear();
+            }
+            IDB_TYPE => {
+                let mut interface = Interface {
+                    linktype: u16_at(body, 0).ok_or("Truncated interface block")?,
+                    ..Default::default()
+                };
+                let mut opt = 8;
+                while let (Some(code), Some(opt_len)) = (u16_at(body, opt), u16_at(body, opt + 2)) {
+                    let value = body.get(opt + 4..opt + 4 + opt_len as usize);
+                    let value = value.map(|value| String::from_utf8_lossy(value).to_string());
+                    match code {
+                        OPT_END => break,
+                        OPT_IF_NAME => interface.name = value,
+                        OPT_IF_DESCRIPTION => interface.description = value,
+                        _ => {}
+                    }
+                    opt += 4 + padded(opt_len as usize);
+                }
+                interfaces.push(interface);
+            }
+            EPB_TYPE => {
+                let interface = u32_at(body, 0).ok_or("Truncated packet block")?;
+                let timestamp = ((u32_at(body, 4).ok_or("Truncated packet block")? as u64) << 32)
+                    | u32_at(body, 8).ok_or("Truncated packet block")? as u64;
+                let captured = u32_at(body, 12).ok_or("Truncated packet block")? as usize;
+                let packet = body.get(20..20 + captured).ok_or("Truncated packet data")?;
+                let linktype = interfaces.get(interface as usize).map(|i| i.linktype);
+                if linktype != Some(LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR)
+                    || packet.len() < 13
+                    || packet[4] != 0x02
+                {
+                    offset += len;
+                    continue;
+                }
+                let direction = match packet[3] & 0x01 {
+                    0 => Direction::Sent,
+                    _ => Direction::Received,
+                };
+                let l2cap_len = u16_at(packet, 9).unwrap_or(0) as usize;
+                let cid = u16_at(packet, 11).unwrap_or(0);
+                let Some(payload) = packet.get(13..13 + l2cap_len) else {
+                    log::debug!("Skipping fragmented packet at {}", offset);
+                    offset += len;
+                    continue;
+                };
+                frames.push(Frame {
+                    interface,
+                    timestamp,
+                    direction,
+                    cid,
+                    data: payload.to_vec(),
+                });
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok((interfaces, frames))
+}
@@ -0,0 +1,355 @@
+use crate::common::{ab_device::ABDevice, transport::Transport};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncBufReadExt;
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// accepts "04 00 04 00", "0x04,0x00" or "04000400"
+pub fn parse_hex(line: &str) -> Result<Vec<u8>, String> {
+    let digits: String = line
+        .split(|c: char| c.is_whitespace() || c == ',' || c == ':')
+        .map(|part| part.trim_start_matches("0x"))
+        .collect();
+    if !digits.is_ascii() {
+        return Err("Frame must only contain hex digits".to_string());
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err("Odd number of hex digits".to_string());
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = &digits[i..i + 2];
+            // from_str_radix would take sign as well
+            u8::from_str_radix(byte, 16)
+                .ok()
+                .filter(|_| byte.bytes().all(|digit| digit.is_ascii_hexdigit()))
+                .ok_or_else(|| format!("Invalid hex byte {:?}", byte))
+        })
+        .collect()
+}
+
+fn battery_status(status: u8) -> String {
+    match status {
+        0x01 => "charging".to_string(),
+        0x02 => "discharging".to_string(),
+        0x04 => "disconnected".to_string(),
+        _ => format!("unknown status {:#04x}", status),
+    }
+}
+
+fn ear(state: u8) -> String {
+    match state {
+        0x00 => "in ear".to_string(),
+        0x01 => "out of ear".to_string(),
+        0x02 => "in case".to_string(),
+        _ => format!("unknown {:#04x}", state),
+    }
+}
+
+// known fields of frame, one per line
+pub fn annotate(buf: &[u8]) -> Vec<String> {
+    let mut lines = vec![];
+    if buf.starts_with(&[0x00, 0x00, 0x04, 0x00, 0x01, 0x00]) {
+        lines.push("handshake".to_string());
+        return lines;
+    }
+    if buf.first() == Some(&0x01) {
+        lines.push("handshake response".to_string());
+        return lines;
+    }
+    let (Some(&opcode), Some(payload)) = (buf.get(4), buf.get(6..)) else {
+        lines.push("too short for AAP frame".to_string());
+        return lines;
+    };
+    match opcode {
+        0x04 => {
+            lines.push(format!(
+                "battery, {} components",
+                payload.first().unwrap_or(&0)
+            ));
+            for sector in payload.get(1..).unwrap_or_default().chunks(5) {
+                let [component, _, level, status, _] = sector else {
+                    lines.push(format!("  truncated component {}", hex(sector)));
+                    continue;
+                };
+                let component = match component {
+                    0x01 => "single".to_string(),
+                    0x02 => "right".to_string(),
+                    0x04 => "left".to_string(),
+                    0x08 => "case".to_string(),
+                    _ => format!("unknown {:#04x}", component),
+                };
+                lines.push(format!(
+                    "  {}: {}%, {}",
+                    component,
+                    level,
+                    battery_status(*status)
+                ));
+            }
+        }
+        0x06 => match payload {
+            [left, right, ..] => {
+                lines.push("ear detection".to_string());
+                lines.push(format!("  left: {}", ear(*left)));
+                lines.push(format!("  right: {}", ear(*right)));
+            }
+            _ => lines.push("ear detection, truncated".to_string()),
+        },
+        0x09 => match payload {
+            [0x0d, mode, ..] => {
+                let mode = match mode {
+                    0x01 => "off".to_string(),
+                    0x02 => "noise cancelling".to_string(),
+                    0x03 => "transparency".to_string(),
+                    0x04 => "adaptive".to_string(),
+                    _ => format!("unknown {:#04x}", mode),
+                };
+                lines.push(format!("setting 0x0d listening mode: {}", mode));
+            }
+            [setting, value @ ..] => {
+                lines.push(format!("setting {:#04x}, unknown: {}", setting, hex(value)));
+            }
+            [] => lines.push("setting, truncated".to_string()),
+        },
+        0x0f => lines.push("notification request".to_string()),
+        0x30 => lines.push("proximity keys request".to_string()),
+        0x31 => {
+            // key values are not printed, only their type and length
+            lines.push(format!(
+                "proximity keys, {} keys",
+                payload.first().unwrap_or(&0)
+            ));
+            let mut offset = 7;
+            for _ in 0..payload.first().copied().unwrap_or(0) {
+                let (Some(key_type), Some(len)) = (buf.get(offset), buf.get(offset + 2)) else {
+                    break;
+                };
+                let key_type = match key_type {
+                    0x01 => "irk".to_string(),
+                    0x04 => "encryption key".to_string(),
+                    _ => format!("unknown {:#04x}", key_type),
+                };
+                lines.push(format!("  {}: {} bytes", key_type, len));
+                offset += 4 + *len as usize;
+            }
+        }
+        _ => lines.push(format!("unknown opcode {:#04x}", opcode)),
+    }
+    lines
+}
+
+fn print(prefix: &str, buf: &[u8]) {
+    // keys are zeroed, console output ends up in bug reports
+    println!("{} {}", prefix, hex(&crate::common::capture::redact(buf)));
+    for line in annotate(buf) {
+        println!("    {}", line);
+    }
+}
+
+// prints every frame and sends hex frames read from stdin
+pub async fn console(
+    addr: Option<bluer::Address>,
+    sim: Option<&Path>,
+    handshake: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ab_device = ABDevice::new();
+    let data_stream: Arc<dyn Transport> = match (sim, addr) {
+        (Some(path), _) => {
+            Arc::new(crate::common::transport::UnixTransport::connect(path, 1024).await?)
+        }
+        (None, Some(addr)) => {
+            let session = bluer::Session::new().await?;
+            let adapter = crate::common::device_manager::adapter_of(&session, addr)
+                .await?
+                .ok_or("Device is not known to any adapter")?;
+            let pods = adapter.device(addr)?;
+            if let Ok(Some(modalias)) = pods.modalias().await {
+                ab_device.model_id = modalias.product;
+            }
+            ab_device
+                .open(pods, adapter)
+                .await
+                .ok_or("Failed to connect to device")?
+        }
+        (None, None) => return Err("Device address is required".into()),
+    };
+    println!("Connected, MTU {}.", data_stream.mtu());
+    if handshake {
+        let init = ab_device
+            .handshake(&data_stream)
+            .await
+            .ok_or("Handshake failed")?;
+        println!("Handshake done.");
+        for frame in init {
+            print("<", &frame);
+        }
+    }
+    println!("Enter hex frames to send.");
+
+    // reading in separate task to keep frames while waiting for stdin
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let reader = data_stream.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; reader.mtu().into()];
+        loop {
+            let received = reader
+                .recv(&mut buf)
+                .await
+                .map(|bytes| buf[..bytes].to_vec());
+            let end = !matches!(&received, Ok(frame) if !frame.is_empty());
+            if tx.send(received).is_err() || end {
+                break;
+            }
+        }
+    });
+
+    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            received = rx.recv() => {
+                let frame = received.ok_or("Reader stopped")??;
+                if frame.is_empty() {
+                    println!("Disconnected");
+                    return Ok(());
+                }
+                print("<", &frame);
+            }
+            line = stdin.next_line() => {
+                let Some(line) = line? else {
+                    let _ = data_stream.shutdown();
+                    return Ok(());
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match parse_hex(&line) {
+                    Ok(frame) => {
+                        data_stream.send(&frame).await?;
+                        print(">", &frame);
+                    }
+                    Err(e) => println!("! {}", e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_with_separators() {
+        let frame = vec![0x04, 0x00, 0x04, 0x00];
+        for line in [
+            "04 00 04 00",
+            "0x04,0x00,0x04,0x00",
+            "04000400",
+            "04:00:04:00",
+            " 04 0004 00 ",
+        ] {
+            assert_eq!(parse_hex(line), Ok(frame.clone()), "{:?}", line);
+        }
+        assert_eq!(parse_hex("0A ff"), Ok(vec![0x0a, 0xff]));
+        assert_eq!(parse_hex(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn rejects_invalid_hex() {
+        assert_eq!(
+            parse_hex("040"),
+            Err("Odd number of hex digits".to_string())
+        );
+        assert_eq!(
+            parse_hex("4 00"),
+            Err("Odd number of hex digits".to_string())
+        );
+        assert_eq!(
+            parse_hex("04 zz"),
+            Err("Invalid hex byte \"zz\"".to_string())
+        );
+        assert_eq!(parse_hex("+1"), Err("Invalid hex byte \"+1\"".to_string()));
+        assert!(parse_hex("0ä").is_err());
+    }
+
+    #[test]
+    fn annotates_frames() {
+        let cases: &[(&[u8], &[&str])] = &[
+            (
+                &[0x00, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02, 0x00],
+                &["handshake"],
+            ),
+            (
+                &[0x01, 0x00, 0x04, 0x00, 0x00, 0x00],
+                &["handshake response"],
+            ),
+            (
+                &[0x04, 0x00, 0x04, 0x00, 0x04],
+                &["too short for AAP frame"],
+            ),
+            (
+                &[
+                    0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x02, 0x04, 0x01, 0x55, 0x02, 0x01, 0x08,
+                    0x01, 0x28,
+                ],
+                &[
+                    "battery, 2 components",
+                    "  left: 85%, discharging",
+                    "  truncated component 08 01 28",
+                ],
+            ),
+            (
+                &[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x02],
+                &["ear detection", "  left: in ear", "  right: in case"],
+            ),
+            (
+                &[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00],
+                &["ear detection, truncated"],
+            ),
+            (
+                &[
+                    0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0d, 0x03, 0x00, 0x00, 0x00,
+                ],
+                &["setting 0x0d listening mode: transparency"],
+            ),
+            (
+                &[0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x1b, 0x02],
+                &["setting 0x1b, unknown: 02"],
+            ),
+            (
+                &[0x04, 0x00, 0x04, 0x00, 0x42, 0x00],
+                &["unknown opcode 0x42"],
+            ),
+        ];
+        for (frame, lines) in cases {
+            assert_eq!(annotate(frame), *lines, "{}", hex(frame));
+        }
+    }
+
+    #[test]
+    fn keys_are_not_shown() {
+        let mut frame = vec![
+            0x04, 0x00, 0x04, 0x00, 0x31, 0x00, 0x02, 0x01, 0x00, 0x10, 0x00,
+        ];
+        frame.extend([0xaa; 16]);
+        frame.extend([0x04, 0x00, 0x10, 0x00]);
+        frame.extend([0xbb; 16]);
+        assert_eq!(
+            annotate(&frame),
+            [
+                "proximity keys, 2 keys",
+                "  irk: 16 bytes",
+                "  encryption key: 16 bytes"
+            ]
+        );
+        let printed = hex(&crate::common::capture::redact(&frame));
+        assert!(!printed.contains("aa") && !printed.contains("bb"));
+    }
+}
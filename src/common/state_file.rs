@@ -0,0 +1,202 @@
+use crate::common::status::{self, DeviceStatus};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast::error::RecvError;
+
+// /tmp is shared by all users, so fallback name carries uid like the socket
+pub fn dir() -> PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(runtime) => PathBuf::from(runtime).join("aplin"),
+        Err(_) => PathBuf::from(format!("/tmp/aplin-{}", crate::common::ipc::uid())),
+    }
+}
+
+// other users could have created it first and plant links inside
+fn create_dir(dir: &Path) -> std::io::Result<()> {
+    if let Err(e) = std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            return Err(e);
+        }
+    }
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != crate::common::ipc::uid() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory owned by current user", dir.display()),
+        ));
+    }
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// sourceable by shell, APLIN_LEFT=80
+fn env(status: &DeviceStatus) -> String {
+    let mut lines = vec![
+        ("APLIN_ADDRESS".to_string(), quote(&status.address)),
+        ("APLIN_NAME".to_string(), quote(&status.name)),
+        ("APLIN_MODEL".to_string(), quote(&status.model)),
+        (
+            "APLIN_MODEL_ID".to_string(),
+            format!("{:#06x}", status.model_id),
+        ),
+        ("APLIN_CONNECTION".to_string(), status.connection.clone()),
+        ("APLIN_ANC".to_string(), status.listening_mode.clone()),
+        ("APLIN_EAR".to_string(), status.ear.clone()),
+    ];
+    for (component, value) in &status.battery {
+        let key = format!("APLIN_{}", component.to_uppercase());
+        lines.push((key.clone(), value.level.to_string()));
+        lines.push((format!("{}_STATE", key), value.state.clone()));
+    }
+    lines
+        .into_iter()
+        .map(|(key, value)| format!("{}={}\n", key, value))
+        .collect()
+}
+
+// readers never see partially written file
+fn replace(path: &Path, content: &str) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    // create_new never follows link left at temp path
+    let _ = std::fs::remove_file(&tmp);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?
+        .write_all(content.as_bytes())?;
+    std::fs::rename(&tmp, path)
+}
+
+fn write(dir: &Path, status: &DeviceStatus) -> Result<(), Box<dyn std::error::Error>> {
+    replace(
+        &dir.join(format!("{}.json", status.address)),
+        &format!("{}\n", serde_json::to_string_pretty(status)?),
+    )?;
+    replace(&dir.join(format!("{}.env", status.address)), &env(status))?;
+    Ok(())
+}
+
+fn remove(dir: &Path, addr: bluer::Address) {
+    for extension in ["json", "env"] {
+        let _ = std::fs::remove_file(dir.join(format!("{}.{}", addr, extension)));
+    }
+}
+
+// rewrites current statuses in place, so readers never see them missing,
+// and drops files of devices that are gone, also those of previous run
+fn sync(dir: &Path, statuses: &[(bluer::Address, DeviceStatus)]) {
+    for (_, status) in statuses {
+        if let Err(e) = write(dir, status) {
+            log::error!("Failed to write state of {}: {}", status.address, e);
+        }
+    }
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::error!("Failed to read state dir {:#?}: {}", dir, e);
+            return;
+        }
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if !path
+            .extension()
+            .is_some_and(|extension| extension == "json" || extension == "env")
+        {
+            continue;
+        }
+        let current = path.file_stem().is_some_and(|stem| {
+            statuses
+                .iter()
+                .any(|(_, status)| stem.eq_ignore_ascii_case(&status.address))
+        });
+        if !current {
+            if let Err(e) = std::fs::remove_file(&path) {
+                log::error!("Failed to remove {:#?}: {}", path, e);
+            }
+        }
+    }
+}
+
+// keeps <addr>.json and <addr>.env in $XDG_RUNTIME_DIR/aplin up to date
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = dir();
+    create_dir(&dir)?;
+    let mut events = status::subscribe();
+    sync(&dir, &status::all());
+    loop {
+        match events.recv().await {
+            Ok((_, Some(status))) => {
+                if let Err(e) = write(&dir, &status) {
+                    log::error!("Failed to write state of {}: {}", status.address, e);
+                }
+            }
+            Ok((addr, None)) => remove(&dir, addr),
+            // missed changes, rewrite everything from current state
+            Err(RecvError::Lagged(_)) => sync(&dir, &status::all()),
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_replaces_current_and_removes_gone() {
+        let dir = std::env::temp_dir().join(format!("aplin-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let addr = bluer::Address::new([0x02, 0, 0, 0, 0x50, 0x01]);
+        let gone = bluer::Address::new([0x02, 0, 0, 0, 0x50, 0x02]);
+        std::fs::write(dir.join(format!("{}.json", addr)), "old").unwrap();
+        std::fs::write(dir.join(format!("{}.env", gone)), "old").unwrap();
+        std::fs::write(dir.join("notes.txt"), "kept").unwrap();
+
+        sync(&dir, &[(addr, DeviceStatus::sample(addr))]);
+
+        let json = std::fs::read_to_string(dir.join(format!("{}.json", addr))).unwrap();
+        assert_eq!(
+            serde_json::from_str::<DeviceStatus>(&json).unwrap(),
+            DeviceStatus::sample(addr)
+        );
+        let env = std::fs::read_to_string(dir.join(format!("{}.env", addr))).unwrap();
+        assert!(env.contains("APLIN_LEFT=80\n"));
+        assert!(!dir.join(format!("{}.env", gone)).exists());
+        assert!(dir.join("notes.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replace_does_not_follow_planted_link() {
+        let dir = std::env::temp_dir().join(format!("aplin-link-{}", std::process::id()));
+        create_dir(&dir).unwrap();
+        let victim = dir.join("victim");
+        std::fs::write(&victim, "untouched").unwrap();
+        let path = dir.join("02:00:00:00:50:03.json");
+        std::os::unix::fs::symlink(&victim, dir.join("02:00:00:00:50:03.json.tmp")).unwrap();
+
+        replace(&path, "new").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&victim).unwrap(), "untouched");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn create_dir_rejects_link() {
+        let dir = std::env::temp_dir().join(format!("aplin-dirlink-{}", std::process::id()));
+        std::os::unix::fs::symlink(std::env::temp_dir(), &dir).unwrap();
+        assert!(create_dir(&dir).is_err());
+        std::fs::remove_file(&dir).unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+use crate::common::ab_battery::ABBatteryState;
+use crate::common::ab_device::ABDevice;
+use crate::common::ab_state::{Anc, ConnectionState, EarCoverState};
+use crate::data::shared_vars::{STATUS, STATUS_EVENTS};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Component {
+    pub level: u8,
+    pub state: String,
+}
+
+// snapshot of device for readers outside of packet loop
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DeviceStatus {
+    pub address: String,
+    pub name: String,
+    pub model: String,
+    pub model_id: u32,
+    pub connection: String,
+    // single, left, right and case, missing when not reported
+    pub battery: BTreeMap<String, Component>,
+    pub listening_mode: String,
+    pub ear: String,
+    pub anc_capable: bool,
+    pub adaptive_capable: bool,
+    // raw 0x09 settings by id, 13 is listening mode
+    pub settings: BTreeMap<u8, u8>,
+}
+
+// fixture shared by tests of status readers
+#[cfg(test)]
+impl DeviceStatus {
+    pub fn sample(addr: bluer::Address) -> Self {
+        DeviceStatus {
+            address: addr.to_string(),
+            name: "work".to_string(),
+            model: "AirPods Pro 2".to_string(),
+            model_id: 0x2014,
+            connection: "ready".to_string(),
+            battery: [(
+                "left".to_string(),
+                Component {
+                    level: 80,
+                    state: "discharging".to_string(),
+                },
+            )]
+            .into(),
+            listening_mode: "off".to_string(),
+            ear: "both".to_string(),
+            anc_capable: true,
+            adaptive_capable: true,
+            settings: [(0x0d, 0x01)].into(),
+        }
+    }
+}
+
+// None once device is gone
+pub type StatusEvent = (bluer::Address, Option<DeviceStatus>);
+
+// single state change, as streamed by aplin watch
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Change {
+    Connected {
+        status: DeviceStatus,
+    },
+    Disconnected,
+    Battery {
+        battery: BTreeMap<String, Component>,
+    },
+    Ear {
+        ear: String,
+    },
+    Anc {
+        mode: String,
+    },
+}
+
+impl Change {
+    // applies change to last known statuses by address
+    pub fn apply(self, address: String, statuses: &mut BTreeMap<String, DeviceStatus>) {
+        match self {
+            Change::Connected { status } => {
+                statuses.insert(address, status);
+            }
+            Change::Disconnected => {
+                statuses.remove(&address);
+            }
+            Change::Battery { battery } => {
+                if let Some(status) = statuses.get_mut(&address) {
+                    status.battery = battery;
+                }
+            }
+            Change::Ear { ear } => {
+                if let Some(status) = statuses.get_mut(&address) {
+                    status.ear = ear;
+                }
+            }
+            Change::Anc { mode } => {
+                if let Some(status) = statuses.get_mut(&address) {
+                    status.listening_mode = mode;
+                }
+            }
+        }
+    }
+}
+
+pub fn changes(old: Option<&DeviceStatus>, new: Option<&DeviceStatus>) -> Vec<Change> {
+    let (old, new) = match (old, new) {
+        (None, None) => return vec![],
+        (None, Some(new)) => {
+            return vec![Change::Connected {
+                status: new.clone(),
+            }]
+        }
+        (Some(_), None) => return vec![Change::Disconnected],
+        (Some(old), Some(new)) => (old, new),
+    };
+    let mut changes = vec![];
+    if old.battery != new.battery {
+        changes.push(Change::Battery {
+            battery: new.battery.clone(),
+        });
+    }
+    if old.ear != new.ear {
+        changes.push(Change::Ear {
+            ear: new.ear.clone(),
+        });
+    }
+    if old.listening_mode != new.listening_mode {
+        changes.push(Change::Anc {
+            mode: new.listening_mode.clone(),
+        });
+    }
+    changes
+}
+
+pub fn battery_state_id(state: ABBatteryState) -> &'static str {
+    match state {
+        ABBatteryState::Charging => "charging",
+        ABBatteryState::Full => "full",
+        ABBatteryState::Discharging | ABBatteryState::Low25 | ABBatteryState::Low10 => {
+            "discharging"
+        }
+        ABBatteryState::Disconnected => "disconnected",
+        ABBatteryState::Unknown => "unknown",
+    }
+}
+
+pub fn anc_id(anc: Anc) -> &'static str {
+    match anc {
+        Anc::Off => "off",
+        Anc::NoiseCancelling => "noise-cancelling",
+        Anc::Transparency => "transparency",
+        Anc::Adaptive => "adaptive",
+    }
+}
+
+pub fn anc_from_id(id: &str) -> Option<Anc> {
+    [
+        Anc::Off,
+        Anc::NoiseCancelling,
+        Anc::Transparency,
+        Anc::Adaptive,
+    ]
+    .into_iter()
+    .find(|anc| anc_id(*anc) == id)
+}
+
+fn ear_id(state: &EarCoverState) -> &'static str {
+    match state {
+        EarCoverState::Both => "both",
+        EarCoverState::Single => "single",
+        EarCoverState::None => "none",
+    }
+}
+
+fn connection_id(state: ConnectionState) -> &'static str {
+    match state {
+        ConnectionState::Disconnected => "disconnected",
+        ConnectionState::Handshaking => "handshaking",
+        ConnectionState::Subscribing => "subscribing",
+        ConnectionState::Ready => "ready",
+    }
+}
+
+impl From<&ABDevice> for DeviceStatus {
+    fn from(device: &ABDevice) -> Self {
+        let battery = device.battery_state;
+        Self {
+            address: device.address.to_string(),
+            name: device.name(),
+            model: device.model.clone(),
+            model_id: device.model_id,
+            connection: connection_id(device.connection_state).to_string(),
+            battery: [
+                ("single", battery.single),
+                ("left", battery.left),
+                ("right", battery.right),
+                ("case", battery.case),
+            ]
+            .into_iter()
+            .filter_map(|(component, value)| {
+                value.map(|(state, level)| {
+                    (
+                        component.to_string(),
+                        Component {
+                            level,
+                            state: battery_state_id(state).to_string(),
+                        },
+                    )
+                })
+            })
+            .collect(),
+            listening_mode: anc_id(device.anc_state).to_string(),
+            ear: ear_id(&device.ear_cover_state).to_string(),
+            anc_capable: device.anc_capable(),
+            adaptive_capable: device.adaptive_capable(),
+            settings: device.settings.clone(),
+        }
+    }
+}
+
+// stores snapshot and tells subscribers if anything changed
+pub fn publish(addr: bluer::Address, status: DeviceStatus) {
+    let mut statuses = STATUS.lock().unwrap();
+    if statuses.get(&addr) == Some(&status) {
+        return;
+    }
+    statuses.insert(addr, status.clone());
+    let _ = STATUS_EVENTS.send((addr, Some(status)));
+}
+
+pub fn remove(addr: bluer::Address) {
+    if STATUS.lock().unwrap().remove(&addr).is_some() {
+        let _ = STATUS_EVENTS.send((addr, None));
+    }
+}
+
+pub fn get(addr: bluer::Address) -> Option<DeviceStatus> {
+    STATUS.lock().unwrap().get(&addr).cloned()
+}
+
+pub fn all() -> Vec<(bluer::Address, DeviceStatus)> {
+    let mut statuses: Vec<_> = STATUS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(addr, status)| (*addr, status.clone()))
+        .collect();
+    statuses.sort_by_key(|(addr, _)| *addr);
+    statuses
+}
+
+pub fn subscribe() -> tokio::sync::broadcast::Receiver<StatusEvent> {
+    STATUS_EVENTS.subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_field_change_is_one_event() {
+        let addr = "02:00:00:00:48:01".parse().unwrap();
+        let old = DeviceStatus::sample(addr);
+
+        let mut new = old.clone();
+        new.battery.get_mut("left").unwrap().level = 70;
+        assert_eq!(
+            changes(Some(&old), Some(&new)),
+            [Change::Battery {
+                battery: new.battery.clone()
+            }]
+        );
+
+        let mut new = old.clone();
+        new.ear = "single".to_string();
+        assert_eq!(
+            changes(Some(&old), Some(&new)),
+            [Change::Ear {
+                ear: "single".to_string()
+            }]
+        );
+
+        let mut new = old.clone();
+        new.listening_mode = "transparency".to_string();
+        assert_eq!(
+            changes(Some(&old), Some(&new)),
+            [Change::Anc {
+                mode: "transparency".to_string()
+            }]
+        );
+
+        // fields without event of their own
+        let mut new = old.clone();
+        new.connection = "subscribing".to_string();
+        new.settings.insert(0x1b, 0x02);
+        assert_eq!(changes(Some(&old), Some(&new)), []);
+        assert_eq!(changes(Some(&old), Some(&old)), []);
+    }
+
+    #[test]
+    fn connect_and_disconnect() {
+        let status = DeviceStatus::sample("02:00:00:00:48:02".parse().unwrap());
+        assert_eq!(
+            changes(None, Some(&status)),
+            [Change::Connected {
+                status: status.clone()
+            }]
+        );
+        assert_eq!(changes(Some(&status), None), [Change::Disconnected]);
+        assert_eq!(changes(None, None), []);
+    }
+
+    #[test]
+    fn applied_changes_give_new_status() {
+        let old = DeviceStatus::sample("02:00:00:00:48:03".parse().unwrap());
+        let mut new = old.clone();
+        new.battery.get_mut("left").unwrap().state = "charging".to_string();
+        new.ear = "none".to_string();
+        new.listening_mode = "adaptive".to_string();
+
+        let mut statuses = BTreeMap::from([(old.address.clone(), old.clone())]);
+        let changes = changes(Some(&old), Some(&new));
+        assert_eq!(changes.len(), 3);
+        for change in changes {
+            change.apply(old.address.clone(), &mut statuses);
+        }
+        assert_eq!(statuses[&old.address], new);
+    }
+}
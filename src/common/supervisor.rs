@@ -0,0 +1,100 @@
+use crate::common::ab_device::{ABDevice, MonitorEnd};
+use crate::data::shared_vars::{BBWATCHING, CONFIG, CONNECTED_MODELS, SESSIONS};
+
+fn panic_message(error: tokio::task::JoinError) -> String {
+    if error.is_cancelled() {
+        return "task was cancelled".to_string();
+    }
+    let payload = error.into_panic();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+async fn monitor(device: bluer::Device, adapter: bluer::Adapter, product_id: u32) -> MonitorEnd {
+    let mut ab_device = ABDevice::new();
+    ab_device.model = match device.name().await {
+        Ok(Some(name)) => name,
+        _ => crate::data::devices::get(product_id)
+            .and_then(|info| info.name)
+            .unwrap_or_else(|| "Unknown".to_string()),
+    };
+    ab_device.model_id = product_id;
+    match ab_device.monitor(device.clone(), adapter).await {
+        Ok(end) => end,
+        Err(e) => {
+            log::error!("Failed to monitor device {}: {}", device.address(), e);
+            MonitorEnd::GaveUp
+        }
+    }
+}
+
+// state a crashed monitor could not clean up itself
+async fn cleanup(addr: bluer::Address) {
+    if let Some(session) = SESSIONS.lock().unwrap().remove(&addr) {
+        session.shutdown();
+    }
+    CONNECTED_MODELS.lock().await.remove(&addr);
+    crate::common::status::remove(addr);
+}
+
+// monitors device in separate task, restarting it after panic while device stays connected
+pub fn spawn(device: bluer::Device, adapter: bluer::Adapter, product_id: u32) {
+    tokio::spawn(async move {
+        let addr = device.address();
+        let (max_restarts, policy) = {
+            let config = CONFIG.lock().unwrap();
+            (config.max_restarts, config.reconnect.policy_for(addr))
+        };
+        let mut restarts = 0;
+        let end = loop {
+            let task = tokio::spawn(monitor(device.clone(), adapter.clone(), product_id));
+            let e = match task.await {
+                Ok(end) => break end,
+                Err(e) => e,
+            };
+            let reason = panic_message(e);
+            log::error!("Monitor of device {} crashed: {}", addr, reason);
+            cleanup(addr).await;
+
+            if restarts >= max_restarts || !device.is_connected().await.unwrap_or(false) {
+                BBWATCHING.lock().await.insert(addr, false);
+                crate::common::commands::default_notification(format!(
+                    "Stopped monitoring {} after error: {}",
+                    addr, reason
+                ))
+                .await;
+                return;
+            }
+            let delay = policy.delay(restarts);
+            restarts += 1;
+            crate::common::commands::default_notification(format!(
+                "Monitoring {} failed, restarting in {}s: {}",
+                addr,
+                delay.as_secs(),
+                reason
+            ))
+            .await;
+            tokio::time::sleep(delay).await;
+        };
+
+        match end {
+            // audio may still work without AAP, connection belongs to user
+            MonitorEnd::GaveUp | MonitorEnd::Unmanaged => {
+                log::debug!("Stopped monitoring {}, keeping it connected", addr);
+                BBWATCHING.lock().await.remove(&addr);
+            }
+            MonitorEnd::Closed => {
+                log::debug!("Device closed {} ", addr);
+                BBWATCHING.lock().await.insert(addr, false);
+                device.disconnect().await.unwrap_or_else(|e| {
+                    log::error!("Failed to disconnect device {}: {}", addr, e);
+                });
+            }
+        }
+    });
+}
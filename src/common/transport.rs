@@ -0,0 +1,179 @@
+use futures::future::BoxFuture;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch, Mutex};
+
+// packet channel device talks AAP over
+pub trait Transport: std::fmt::Debug + Send + Sync {
+    fn send<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, std::io::Result<usize>>;
+    // returns 0 once transport is shut down
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, std::io::Result<usize>>;
+    fn mtu(&self) -> u16;
+    fn shutdown(&self) -> std::io::Result<()>;
+}
+
+#[derive(Debug)]
+pub struct L2capTransport {
+    socket: bluer::l2cap::SeqPacket,
+    mtu: u16,
+}
+
+impl L2capTransport {
+    pub fn new(socket: bluer::l2cap::SeqPacket) -> std::io::Result<Self> {
+        let mtu = socket.as_ref().recv_mtu()?;
+        Ok(Self { socket, mtu })
+    }
+}
+
+impl Transport for L2capTransport {
+    fn send<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(self.socket.send(data))
+    }
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(self.socket.recv(buf))
+    }
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+    fn shutdown(&self) -> std::io::Result<()> {
+        self.socket.shutdown(std::net::Shutdown::Both)
+    }
+}
+
+// in-memory packet channel, one end acts as device, other as host
+#[derive(Debug)]
+pub struct MemoryTransport {
+    // dropped on shutdown so other end receives end of stream
+    tx: std::sync::Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>,
+    rx: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    closed: watch::Sender<bool>,
+    mtu: u16,
+}
+
+impl MemoryTransport {
+    pub fn pair(mtu: u16) -> (Self, Self) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                tx: std::sync::Mutex::new(Some(a_tx)),
+                rx: Mutex::new(b_rx),
+                closed: watch::Sender::new(false),
+                mtu,
+            },
+            Self {
+                tx: std::sync::Mutex::new(Some(b_tx)),
+                rx: Mutex::new(a_rx),
+                closed: watch::Sender::new(false),
+                mtu,
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(async move {
+            let tx = self.tx.lock().unwrap();
+            let Some(tx) = tx.as_ref() else {
+                return Err(std::io::ErrorKind::NotConnected.into());
+            };
+            tx.send(data.to_vec())
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+            Ok(data.len())
+        })
+    }
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(async move {
+            let mut closed = self.closed.subscribe();
+            if *closed.borrow_and_update() {
+                return Ok(0);
+            }
+            let mut rx = self.rx.lock().await;
+            tokio::select! {
+                packet = rx.recv() => match packet {
+                    Some(packet) => {
+                        // same as seqpacket, data over buffer size is dropped
+                        let len = packet.len().min(buf.len());
+                        buf[..len].copy_from_slice(&packet[..len]);
+                        Ok(len)
+                    }
+                    None => Ok(0),
+                },
+                _ = closed.changed() => Ok(0),
+            }
+        })
+    }
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+    fn shutdown(&self) -> std::io::Result<()> {
+        self.tx.lock().unwrap().take();
+        self.closed.send_replace(true);
+        Ok(())
+    }
+}
+
+// unix stream socket used to talk to aplin-sim, packets are prefixed with u16 le length
+#[derive(Debug)]
+pub struct UnixTransport {
+    // read half with bytes of not yet complete packet
+    reader: Mutex<(tokio::net::unix::OwnedReadHalf, Vec<u8>)>,
+    writer: Mutex<tokio::net::unix::OwnedWriteHalf>,
+    // std handle to shut socket down without awaiting
+    control: std::os::unix::net::UnixStream,
+    mtu: u16,
+}
+
+impl UnixTransport {
+    pub async fn connect(path: &std::path::Path, mtu: u16) -> std::io::Result<Self> {
+        let stream = tokio::net::UnixStream::connect(path).await?.into_std()?;
+        let control = stream.try_clone()?;
+        let (reader, writer) = tokio::net::UnixStream::from_std(stream)?.into_split();
+        Ok(Self {
+            reader: Mutex::new((reader, vec![])),
+            writer: Mutex::new(writer),
+            control,
+            mtu,
+        })
+    }
+}
+
+impl Transport for UnixTransport {
+    fn send<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(async move {
+            let len = u16::try_from(data.len())
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+            let mut writer = self.writer.lock().await;
+            writer.write_all(&len.to_le_bytes()).await?;
+            writer.write_all(data).await?;
+            Ok(data.len())
+        })
+    }
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(async move {
+            // read_buf keeps partial data when cancelled, e.g. by timeout
+            let mut guard = self.reader.lock().await;
+            let (reader, pending) = &mut *guard;
+            loop {
+                if pending.len() >= 2 {
+                    let len = u16::from_le_bytes([pending[0], pending[1]]) as usize;
+                    if pending.len() >= 2 + len {
+                        let packet: Vec<u8> = pending.drain(..2 + len).skip(2).collect();
+                        let len = packet.len().min(buf.len());
+                        buf[..len].copy_from_slice(&packet[..len]);
+                        return Ok(len);
+                    }
+                }
+                if reader.read_buf(pending).await? == 0 {
+                    return Ok(0);
+                }
+            }
+        })
+    }
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+    fn shutdown(&self) -> std::io::Result<()> {
+        self.control.shutdown(std::net::Shutdown::Both)
+    }
+}
@@ -1,4 +1,7 @@
+use crate::common::ab_state::EarCoverState;
+use crate::data::devices::DeviceEntry;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
 use std::path::PathBuf;
@@ -10,10 +13,25 @@ pub struct ConfigRead {
     pub command_none: Option<String>,
     pub notification_timeout: Option<u32>,
     pub disconnect_timeout: Option<u64>,
+    pub max_restarts: Option<u32>,
+    pub handshake_timeout: Option<u64>,
+    pub handshake_retries: Option<u32>,
     pub notify_on_full_charge: Option<bool>,
     pub notify_on_25_percent: Option<bool>,
     pub notify_on_10_percent: Option<bool>,
     pub notify_on_anc_change: Option<bool>,
+    pub proximity: Option<bool>,
+    pub dbus: Option<bool>,
+    pub bar_template: Option<String>,
+    pub state_files: Option<bool>,
+    pub adapters: Option<Vec<String>>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub only: Option<String>,
+    pub auto_connect: Option<AutoConnect>,
+    pub reconnect: Option<Reconnect>,
+    pub pods: Option<HashMap<String, PodsEntry>>,
+    pub devices: Option<HashMap<u32, DeviceEntry>>,
 }
 
 impl ConfigRead {
@@ -29,6 +47,13 @@ impl ConfigRead {
             disconnect_timeout: self
                 .disconnect_timeout
                 .unwrap_or(default_config.disconnect_timeout),
+            max_restarts: self.max_restarts.unwrap_or(default_config.max_restarts),
+            handshake_timeout: self
+                .handshake_timeout
+                .unwrap_or(default_config.handshake_timeout),
+            handshake_retries: self
+                .handshake_retries
+                .unwrap_or(default_config.handshake_retries),
             notify_on_full_charge: self
                 .notify_on_full_charge
                 .unwrap_or(default_config.notify_on_full_charge),
@@ -41,10 +66,124 @@ impl ConfigRead {
             notify_on_anc_change: self
                 .notify_on_anc_change
                 .unwrap_or(default_config.notify_on_anc_change),
+            proximity: self.proximity.unwrap_or(default_config.proximity),
+            dbus: self.dbus.unwrap_or(default_config.dbus),
+            bar_template: self.bar_template.unwrap_or(default_config.bar_template),
+            state_files: self.state_files.unwrap_or(default_config.state_files),
+            adapters: self.adapters.unwrap_or(default_config.adapters),
+            allow: self.allow.unwrap_or(default_config.allow),
+            deny: self.deny.unwrap_or(default_config.deny),
+            only: self.only.or(default_config.only),
+            auto_connect: self.auto_connect.unwrap_or(default_config.auto_connect),
+            reconnect: self.reconnect.unwrap_or(default_config.reconnect),
+            pods: self.pods.unwrap_or(default_config.pods),
+            devices: self.devices.unwrap_or(default_config.devices),
         }
     }
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AutoConnect {
+    pub enabled: bool,
+    pub rssi_threshold: i16,
+    // per device override by bluetooth address
+    pub devices: HashMap<String, bool>,
+}
+
+impl Default for AutoConnect {
+    fn default() -> Self {
+        AutoConnect {
+            enabled: false,
+            rssi_threshold: -60,
+            devices: HashMap::new(),
+        }
+    }
+}
+
+impl AutoConnect {
+    pub fn enabled_for(&self, addr: bluer::Address) -> bool {
+        self.devices
+            .iter()
+            .find(|(device, _)| device.parse::<bluer::Address>().ok() == Some(addr))
+            .map(|(_, enabled)| *enabled)
+            .unwrap_or(self.enabled)
+    }
+}
+
+// settings of single device by bluetooth address, commands override global ones
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PodsEntry {
+    pub alias: Option<String>,
+    pub command_both: Option<String>,
+    pub command_single: Option<String>,
+    pub command_none: Option<String>,
+}
+
+// retries of AAP channel while device stays connected, delays in seconds
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Reconnect {
+    pub max_attempts: u32,
+    pub initial_delay: u64,
+    pub max_delay: u64,
+    // per device override by bluetooth address
+    pub devices: HashMap<String, ReconnectEntry>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ReconnectEntry {
+    pub max_attempts: Option<u32>,
+    pub initial_delay: Option<u64>,
+    pub max_delay: Option<u64>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: u64,
+    pub max_delay: u64,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect {
+            max_attempts: 5,
+            initial_delay: 1,
+            max_delay: 60,
+            devices: HashMap::new(),
+        }
+    }
+}
+
+impl Reconnect {
+    pub fn policy_for(&self, addr: bluer::Address) -> ReconnectPolicy {
+        let entry = self
+            .devices
+            .iter()
+            .find(|(device, _)| device.parse::<bluer::Address>().ok() == Some(addr))
+            .map(|(_, entry)| entry.clone())
+            .unwrap_or_default();
+        ReconnectPolicy {
+            max_attempts: entry.max_attempts.unwrap_or(self.max_attempts),
+            initial_delay: entry.initial_delay.unwrap_or(self.initial_delay),
+            max_delay: entry.max_delay.unwrap_or(self.max_delay),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    // doubles with every attempt, starting at 0
+    pub fn delay(&self, attempt: u32) -> std::time::Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u64.saturating_pow(attempt))
+            .min(self.max_delay);
+        std::time::Duration::from_secs(delay)
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Config {
     pub command_both: Option<String>,
@@ -52,10 +191,33 @@ pub struct Config {
     pub command_none: Option<String>,
     pub notification_timeout: u32,
     pub disconnect_timeout: u64,
+    // restarts of device monitor after it crashed
+    pub max_restarts: u32,
+    // seconds to wait for every handshake step
+    pub handshake_timeout: u64,
+    pub handshake_retries: u32,
     pub notify_on_full_charge: bool,
     pub notify_on_25_percent: bool,
     pub notify_on_10_percent: bool,
     pub notify_on_anc_change: bool,
+    pub proximity: bool,
+    // io.github.aplin service on session bus
+    pub dbus: bool,
+    // text of aplin bar, see bar::render for placeholders
+    pub bar_template: String,
+    // $XDG_RUNTIME_DIR/aplin/<address>.json and .env
+    pub state_files: bool,
+    // adapter names like hci1, all adapters are used when empty
+    pub adapters: Vec<String>,
+    // devices by address, alias or model name, all are managed when allow is empty
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    // single managed device, overrides allow and deny
+    pub only: Option<String>,
+    pub auto_connect: AutoConnect,
+    pub reconnect: Reconnect,
+    pub pods: HashMap<String, PodsEntry>,
+    pub devices: HashMap<u32, DeviceEntry>,
 }
 
 impl Default for Config {
@@ -66,15 +228,108 @@ impl Default for Config {
             command_none: None,
             notification_timeout: 5,
             disconnect_timeout: 60,
+            max_restarts: 3,
+            handshake_timeout: 2,
+            handshake_retries: 3,
             notify_on_full_charge: true,
             notify_on_25_percent: true,
             notify_on_10_percent: true,
             notify_on_anc_change: false,
+            proximity: true,
+            dbus: true,
+            bar_template: "{battery} {anc}".to_string(),
+            state_files: true,
+            adapters: vec![],
+            allow: vec![],
+            deny: vec![],
+            only: None,
+            auto_connect: AutoConnect::default(),
+            reconnect: Reconnect::default(),
+            pods: HashMap::new(),
+            devices: HashMap::new(),
         }
     }
 }
 
 impl Config {
+    fn pods_entry(&self, addr: bluer::Address) -> Option<&PodsEntry> {
+        self.pods
+            .iter()
+            .find(|(device, _)| device.parse::<bluer::Address>().ok() == Some(addr))
+            .map(|(_, entry)| entry)
+    }
+
+    pub fn alias(&self, addr: bluer::Address) -> Option<String> {
+        self.pods_entry(addr).and_then(|entry| entry.alias.clone())
+    }
+
+    // command run when ear state changes to given one
+    pub fn hook(&self, addr: bluer::Address, state: &EarCoverState) -> Option<String> {
+        let entry = self.pods_entry(addr);
+        match state {
+            EarCoverState::Both => entry
+                .and_then(|entry| entry.command_both.clone())
+                .or_else(|| self.command_both.clone()),
+            EarCoverState::Single => entry
+                .and_then(|entry| entry.command_single.clone())
+                .or_else(|| self.command_single.clone()),
+            EarCoverState::None => entry
+                .and_then(|entry| entry.command_none.clone())
+                .or_else(|| self.command_none.clone()),
+        }
+    }
+
+    // device selector used by cli and ipc, bluetooth address or alias
+    pub fn resolve_device(&self, selector: &str) -> Option<bluer::Address> {
+        if let Ok(addr) = selector.parse() {
+            return Some(addr);
+        }
+        self.pods.iter().find_map(|(device, entry)| {
+            entry
+                .alias
+                .as_deref()
+                .filter(|alias| alias.eq_ignore_ascii_case(selector))
+                .and_then(|_| device.parse().ok())
+        })
+    }
+
+    fn matches(&self, entry: &str, addr: bluer::Address, model: &str) -> bool {
+        entry.parse::<bluer::Address>().ok() == Some(addr)
+            || entry.eq_ignore_ascii_case(model)
+            || self
+                .alias(addr)
+                .is_some_and(|alias| alias.eq_ignore_ascii_case(entry))
+    }
+
+    // empty model is not known yet, entry naming a model may still match it,
+    // session is checked again once metadata tells the model
+    fn may_match(&self, entry: &str, addr: bluer::Address, model: &str) -> bool {
+        self.matches(entry, addr, model)
+            || (model.is_empty()
+                && entry.parse::<bluer::Address>().is_err()
+                && !self.pods.values().any(|pods| {
+                    pods.alias
+                        .as_deref()
+                        .is_some_and(|alias| alias.eq_ignore_ascii_case(entry))
+                }))
+    }
+
+    // whether aplin should open AAP session to device at all
+    pub fn managed(&self, addr: bluer::Address, model: &str) -> bool {
+        if let Some(only) = &self.only {
+            return self.may_match(only, addr, model);
+        }
+        !self
+            .deny
+            .iter()
+            .any(|entry| self.matches(entry, addr, model))
+            && (self.allow.is_empty()
+                || self
+                    .allow
+                    .iter()
+                    .any(|entry| self.may_match(entry, addr, model)))
+    }
+
     pub fn load(path: Option<PathBuf>) -> Self {
         let config = Config::default();
         let path_buf = if let Some(p) = path {
@@ -109,6 +364,8 @@ impl Config {
             };
         };
 
+        let devices_path = path_buf.with_file_name("devices.yaml");
+
         let config_content_raw = match fs::read_to_string(path_buf) {
             Ok(content) => content,
             Err(e) => {
@@ -118,6 +375,103 @@ impl Config {
             }
         };
         let config_read: ConfigRead = serde_yml::from_str(&config_content_raw).unwrap();
-        config_read.into_config()
+        let mut config = config_read.into_config();
+
+        config.devices = merge_devices(
+            crate::data::devices::load_file(&devices_path),
+            std::mem::take(&mut config.devices),
+        );
+        config
+    }
+}
+
+// fields set in config take precedence over devices.yaml
+fn merge_devices(
+    mut devices: HashMap<u32, DeviceEntry>,
+    config: HashMap<u32, DeviceEntry>,
+) -> HashMap<u32, DeviceEntry> {
+    for (id, entry) in config {
+        devices.entry(id).or_default().merge(entry);
+    }
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Config {
+        serde_yml::from_str::<ConfigRead>(yaml)
+            .unwrap()
+            .into_config()
+    }
+
+    // same lookup device manager does before opening AAP session
+    fn managed(config: &Config, addr: &str, product_id: u32) -> bool {
+        let model = crate::data::devices::get(product_id)
+            .and_then(|info| info.name)
+            .unwrap_or_default();
+        config.managed(addr.parse().unwrap(), &model)
+    }
+
+    #[test]
+    fn deny_by_model_name() {
+        let config = parse("deny: [\"AirPods Max\"]\n");
+        assert!(!managed(&config, "11:22:33:44:55:66", 0x200A));
+        assert!(managed(&config, "11:22:33:44:55:66", 0x2014));
+    }
+
+    #[test]
+    fn allow_by_alias_and_only() {
+        let config = parse("pods:\n  \"11:22:33:44:55:66\":\n    alias: work\nallow: [work]\n");
+        assert!(managed(&config, "11:22:33:44:55:66", 0x2014));
+        assert!(!managed(&config, "11:22:33:44:55:77", 0x2014));
+
+        let config = parse("deny: [\"AirPods Pro 2\"]\nonly: \"AirPods Pro 2\"\n");
+        assert!(managed(&config, "11:22:33:44:55:66", 0x2014));
+        assert!(!managed(&config, "11:22:33:44:55:66", 0x200E));
+    }
+
+    #[test]
+    fn unknown_model_is_checked_later() {
+        let config = parse("only: \"AirPods Pro 2\"\n");
+        assert!(managed(&config, "11:22:33:44:55:66", 0));
+        let config = parse("deny: [\"AirPods Pro 2\"]\n");
+        assert!(managed(&config, "11:22:33:44:55:66", 0));
+        // address and alias are known without model
+        let config = parse("pods:\n  \"11:22:33:44:55:66\":\n    alias: work\nallow: [work]\n");
+        assert!(!managed(&config, "11:22:33:44:55:77", 0));
+        let config = parse("only: \"11:22:33:44:55:66\"\n");
+        assert!(!managed(&config, "11:22:33:44:55:77", 0));
+    }
+
+    #[test]
+    fn parses_hex_device_ids() {
+        let config = parse("devices:\n  0x2027:\n    icon: onear\n  8212:\n    anc: true\n");
+        assert_eq!(
+            config.devices[&0x2027].icon,
+            Some(crate::data::devices::DeviceIcon::Onear)
+        );
+        assert_eq!(config.devices[&0x2014].anc, Some(true));
+    }
+
+    #[test]
+    fn config_device_fields_override_devices_file() {
+        let file: HashMap<u32, DeviceEntry> = serde_yml::from_str(
+            "0x2027:\n  name: Studio Buds X\n  anc: true\n  model_numbers: [A3001]\n0x2028:\n  name: Other\n",
+        )
+        .unwrap();
+        let config = parse(
+            "devices:\n  0x2027:\n    icon: onear\n    anc: false\n  0x2029:\n    name: New\n",
+        );
+        let devices = merge_devices(file, config.devices);
+
+        let merged = &devices[&0x2027];
+        assert_eq!(merged.name.as_deref(), Some("Studio Buds X"));
+        assert_eq!(merged.anc, Some(false));
+        assert_eq!(merged.icon, Some(crate::data::devices::DeviceIcon::Onear));
+        assert_eq!(merged.model_numbers, Some(vec!["A3001".to_string()]));
+        assert_eq!(devices[&0x2028].name.as_deref(), Some("Other"));
+        assert_eq!(devices[&0x2029].name.as_deref(), Some("New"));
     }
 }
@@ -0,0 +1,183 @@
+use crate::data::shared_vars::{
+    AB_DEVICES, AB_EARHOOK, AB_MODEL_NUMBERS, AB_MONITORS, AB_ONEAR, AB_STEMLESS, ADAPTIVE_CAPABLE,
+    ANC_CAPABLE, DEVICES,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// form factor, used to pick tray icon
+#[derive(Debug, Copy, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceIcon {
+    #[default]
+    Buds,
+    Stemless,
+    Earhook,
+    Onear,
+    Monitors,
+}
+
+impl DeviceIcon {
+    pub fn name(&self) -> &str {
+        match self {
+            DeviceIcon::Buds => "headphones-buds",
+            DeviceIcon::Stemless => "headphones-stemless",
+            DeviceIcon::Earhook => "headphones-earhook",
+            DeviceIcon::Onear => "headphones-onear",
+            DeviceIcon::Monitors => "headphones-monitors",
+        }
+    }
+}
+
+// capabilities of a single product id
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInfo {
+    pub name: Option<String>,
+    pub anc: bool,
+    pub adaptive: bool,
+    pub icon: DeviceIcon,
+    // like A2084, used when device has no modalias
+    pub model_numbers: Vec<String>,
+}
+
+// user supplied entry, every field is optional so built-in values
+// can be partially overridden
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DeviceEntry {
+    pub name: Option<String>,
+    pub anc: Option<bool>,
+    pub adaptive: Option<bool>,
+    pub icon: Option<DeviceIcon>,
+    pub model_numbers: Option<Vec<String>>,
+}
+
+impl DeviceEntry {
+    // fields set in other entry win, the rest is kept
+    pub fn merge(&mut self, other: DeviceEntry) {
+        self.name = other.name.or(self.name.take());
+        self.anc = other.anc.or(self.anc);
+        self.adaptive = other.adaptive.or(self.adaptive);
+        self.icon = other.icon.or(self.icon);
+        self.model_numbers = other.model_numbers.or(self.model_numbers.take());
+    }
+
+    fn apply(&self, info: &mut DeviceInfo) {
+        if let Some(name) = &self.name {
+            info.name = Some(name.clone());
+        }
+        if let Some(anc) = self.anc {
+            info.anc = anc;
+        }
+        if let Some(adaptive) = self.adaptive {
+            info.adaptive = adaptive;
+        }
+        if let Some(icon) = self.icon {
+            info.icon = icon;
+        }
+        if let Some(model_numbers) = &self.model_numbers {
+            info.model_numbers = model_numbers.clone();
+        }
+    }
+}
+
+pub fn builtin() -> HashMap<u32, DeviceInfo> {
+    AB_DEVICES
+        .iter()
+        .map(|(id, name)| {
+            (
+                *id,
+                DeviceInfo {
+                    name: Some(name.to_string()),
+                    anc: ANC_CAPABLE.contains(id),
+                    adaptive: ADAPTIVE_CAPABLE.contains(id),
+                    icon: builtin_icon(*id),
+                    model_numbers: AB_MODEL_NUMBERS
+                        .iter()
+                        .filter(|(_, model_id)| model_id == id)
+                        .map(|(model_number, _)| model_number.to_string())
+                        .collect(),
+                },
+            )
+        })
+        .collect()
+}
+
+fn builtin_icon(product_id: u32) -> DeviceIcon {
+    if AB_MONITORS.contains(&product_id) {
+        DeviceIcon::Monitors
+    } else if AB_ONEAR.contains(&product_id) {
+        DeviceIcon::Onear
+    } else if AB_EARHOOK.contains(&product_id) {
+        DeviceIcon::Earhook
+    } else if AB_STEMLESS.contains(&product_id) {
+        DeviceIcon::Stemless
+    } else {
+        DeviceIcon::Buds
+    }
+}
+
+// separate devices file lives next to the config, same format as `devices:` section
+pub fn load_file(path: &Path) -> HashMap<u32, DeviceEntry> {
+    if !path.exists() {
+        return HashMap::new();
+    }
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            log::error!("Error reading devices file {:#?}: {}", path, e);
+            return HashMap::new();
+        }
+    };
+    match serde_yml::from_str(&content) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::error!("Error parsing devices file {:#?}: {}", path, e);
+            HashMap::new()
+        }
+    }
+}
+
+// merge user entries on top of built-in table
+pub fn load(entries: &HashMap<u32, DeviceEntry>) {
+    let mut devices = builtin();
+    for (id, entry) in entries {
+        log::debug!("Device entry from config: {:#06x} {:?}", id, entry);
+        entry.apply(devices.entry(*id).or_default());
+    }
+    *DEVICES.lock().unwrap() = devices;
+}
+
+pub fn get(product_id: u32) -> Option<DeviceInfo> {
+    DEVICES.lock().unwrap().get(&product_id).cloned()
+}
+
+pub fn by_model_number(model_number: &str) -> Option<u32> {
+    DEVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, info)| {
+            info.model_numbers
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(model_number))
+        })
+        .map(|(id, _)| *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_overrides_only_set_fields() {
+        let mut info = builtin()[&0x2014].clone();
+        let entry: DeviceEntry = serde_yml::from_str("icon: onear\n").unwrap();
+        entry.apply(&mut info);
+        assert_eq!(info.icon, DeviceIcon::Onear);
+        assert_eq!(info.name.as_deref(), Some("AirPods Pro 2"));
+        assert!(info.anc && info.adaptive);
+        assert!(info.model_numbers.contains(&"A2698".to_string()));
+    }
+}
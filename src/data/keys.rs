@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+// keys received from device over AAP, used to resolve and decrypt its advertisements
+#[derive(Debug, Clone, Copy)]
+pub struct ProximityKeys {
+    pub model_id: u32,
+    pub irk: [u8; 16],
+    pub enc_key: [u8; 16],
+}
+
+// on-disk representation, keys are stored as hex strings
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ProximityKeysRaw {
+    model_id: u32,
+    irk: String,
+    enc_key: String,
+}
+
+impl ProximityKeysRaw {
+    fn into_keys(self) -> Option<ProximityKeys> {
+        Some(ProximityKeys {
+            model_id: self.model_id,
+            irk: from_hex(&self.irk)?,
+            enc_key: from_hex(&self.enc_key)?,
+        })
+    }
+    fn from_keys(keys: &ProximityKeys) -> Self {
+        Self {
+            model_id: keys.model_id,
+            irk: to_hex(&keys.irk),
+            enc_key: to_hex(&keys.enc_key),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<[u8; 16]> {
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+pub fn state_dir() -> PathBuf {
+    if let Ok(state) = std::env::var("XDG_STATE_HOME") {
+        return PathBuf::from(state).join("aplin");
+    }
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(format!("{}/.local/state/aplin", home))
+}
+
+fn keys_path() -> PathBuf {
+    state_dir().join("keys.yaml")
+}
+
+fn load_raw() -> HashMap<String, ProximityKeysRaw> {
+    let path = keys_path();
+    if !path.exists() {
+        return HashMap::new();
+    }
+    match fs::read_to_string(&path) {
+        Ok(content) => serde_yml::from_str(&content).unwrap_or_else(|e| {
+            log::error!("Error parsing keys file {:#?}: {}", path, e);
+            HashMap::new()
+        }),
+        Err(e) => {
+            log::error!("Error reading keys file {:#?}: {}", path, e);
+            HashMap::new()
+        }
+    }
+}
+
+// keys by bluetooth address of device
+pub fn load() -> HashMap<bluer::Address, ProximityKeys> {
+    load_raw()
+        .into_iter()
+        .filter_map(|(addr, raw)| Some((addr.parse().ok()?, raw.into_keys()?)))
+        .collect()
+}
+
+pub fn save(addr: bluer::Address, keys: &ProximityKeys) {
+    let mut all = load_raw();
+    all.insert(addr.to_string(), ProximityKeysRaw::from_keys(keys));
+
+    let dir = state_dir();
+    if let Err(e) = fs::create_dir_all(&dir) {
+        log::error!("Error creating state directory {:#?}: {}", dir, e);
+        return;
+    }
+    let content = match serde_yml::to_string(&all) {
+        Ok(content) => content,
+        Err(e) => {
+            log::error!("Error serializing keys: {}", e);
+            return;
+        }
+    };
+    let path = keys_path();
+    // keys allow tracking the device, keep them private
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path);
+    match file.and_then(|mut file| file.write_all(content.as_bytes())) {
+        Ok(_) => log::debug!("Saved proximity keys for {} to {:#?}", addr, path),
+        Err(e) => log::error!("Error writing keys file {:#?}: {}", path, e),
+    }
+}
@@ -1,2 +1,4 @@
 pub mod config;
+pub mod devices;
+pub mod keys;
 pub mod shared_vars;
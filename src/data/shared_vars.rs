@@ -1,23 +1,49 @@
 use crate::data::config::Config;
+use crate::data::devices::DeviceInfo;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-pub const AB_DEVICES: &[u32] = &[
-    0x2002, // AirPods 1
-    0x200F, // AirPods 2
-    0x2013, // AirPods 3
-    0x2019, // AirPods 4
+// product ids with model names, names can be used in allow and deny lists
+pub const AB_DEVICES: &[(u32, &str)] = &[
+    (0x2002, "AirPods"),
+    (0x200F, "AirPods 2"),
+    (0x2013, "AirPods 3"),
+    (0x2019, "AirPods 4"),
+    (0x201B, "AirPods 4 ANC"),
+    (0x200E, "AirPods Pro"),
+    (0x2014, "AirPods Pro 2"),
+    (0x2024, "AirPods Pro 2 USB-C"),
+    (0x200A, "AirPods Max"),
+    (0x201f, "AirPods Max USB-C"),
+    (0x200B, "Powerbeats Pro"),
+    (0x201D, "Powerbeats Pro 2"),
+    (0x200C, "Beats Solo Pro"),
+    (0x2011, "Beats Studio Buds"),
+    (0x2012, "Beats Fit Pro"),
+    (0x2016, "Beats Studio Buds+"),
+    (0x2017, "Beats Studio Pro"),
+    (0x2025, "Beats Solo 4"),
+    (0x2026, "Beats Solo Buds"),
+];
+
+pub const ANC_CAPABLE: &[u32] = &[
+    0x201B, // AirPods 4 ANC
     0x200E, // AirPods Pro
     0x2014, // AirPods Pro 2
     0x2024, // AirPods Pro 2 usb-c
     0x200A, // AirPods Max lightning
     0x201f, // AirPods Max usb-c 2024
-    0x2012, // Beats Feat Pro
+    0x201D, // Powerbeats Pro 2
+    0x200C, // Beats Solo Pro
+    0x2011, // Beats Studio Buds
+    0x2012, // Beats Fit Pro
+    0x2016, // Beats Studio Buds+
+    0x2017, // Beats Studio Pro
 ];
 
 pub const ADAPTIVE_CAPABLE: &[u32] = &[
-    0x2019, // AirPods 4
+    0x201B, // AirPods 4 ANC
     0x2014, // AirPods Pro 2
     0x2024, // AirPods Pro 2 usb-c
 ];
@@ -26,9 +52,92 @@ pub const ADAPTIVE_CAPABLE: &[u32] = &[
 pub const AB_MONITORS: &[u32] = &[
     0x200A, // AirPods Max lightning
     0x201f, // AirPods Max usb-c 2024
+    0x2017, // Beats Studio Pro
+];
+
+// on-ear headphones
+pub const AB_ONEAR: &[u32] = &[
+    0x200C, // Beats Solo Pro
+    0x2025, // Beats Solo 4
+];
+
+// buds with ear hooks
+pub const AB_EARHOOK: &[u32] = &[
+    0x200B, // Powerbeats Pro
+    0x201D, // Powerbeats Pro 2
+];
+
+// buds without stem
+pub const AB_STEMLESS: &[u32] = &[
+    0x2011, // Beats Studio Buds
+    0x2012, // Beats Fit Pro
+    0x2016, // Beats Studio Buds+
+    0x2026, // Beats Solo Buds
+];
+
+// default names of devices, for devices without modalias
+// names shared by several generations (AirPods, AirPods Pro, AirPods Max) are left out,
+// their model is known once AAP metadata arrives
+pub const AB_NAMES: &[(&str, u32)] = &[
+    ("Powerbeats Pro", 0x200B),
+    ("Powerbeats Pro 2", 0x201D),
+    ("Beats Solo Pro", 0x200C),
+    ("Beats Studio Buds", 0x2011),
+    ("Beats Fit Pro", 0x2012),
+    ("Beats Studio Buds+", 0x2016),
+    ("Beats Studio Pro", 0x2017),
+    ("Beats Solo 4", 0x2025),
+    ("Beats Solo Buds", 0x2026),
+];
+
+// model numbers sent in AAP metadata packet
+pub const AB_MODEL_NUMBERS: &[(&str, u32)] = &[
+    ("A1523", 0x2002),
+    ("A1722", 0x2002),
+    ("A2031", 0x200F),
+    ("A2032", 0x200F),
+    ("A2564", 0x2013),
+    ("A2565", 0x2013),
+    ("A3050", 0x2019),
+    ("A3053", 0x2019),
+    ("A3055", 0x201B),
+    ("A3056", 0x201B),
+    ("A2083", 0x200E),
+    ("A2084", 0x200E),
+    ("A2698", 0x2014),
+    ("A2699", 0x2014),
+    ("A3047", 0x2024),
+    ("A3048", 0x2024),
+    ("A2096", 0x200A),
+    ("A3184", 0x201f),
 ];
 
 pub static BBWATCHING: Lazy<Arc<tokio::sync::Mutex<HashMap<bluer::Address, bool>>>> =
     Lazy::new(|| Arc::new(tokio::sync::Mutex::new(HashMap::new())));
 
+// model ids of devices with open AAP session
+pub static CONNECTED_MODELS: Lazy<Arc<tokio::sync::Mutex<HashMap<bluer::Address, u32>>>> =
+    Lazy::new(|| Arc::new(tokio::sync::Mutex::new(HashMap::new())));
+
+// running AAP sessions, for teardown from outside of packet loop
+pub static SESSIONS: Lazy<Mutex<HashMap<bluer::Address, crate::common::ab_device::Session>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// latest status of devices with open AAP session
+pub static STATUS: Lazy<Mutex<HashMap<bluer::Address, crate::common::status::DeviceStatus>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// status changes for dbus service and other subscribers
+pub static STATUS_EVENTS: Lazy<tokio::sync::broadcast::Sender<crate::common::status::StatusEvent>> =
+    Lazy::new(|| tokio::sync::broadcast::channel(64).0);
+
+// pcapng writer, set when started with --capture
+pub static CAPTURE: Lazy<
+    Mutex<Option<crate::common::pcapng::Writer<std::io::BufWriter<std::fs::File>>>>,
+> = Lazy::new(|| Mutex::new(None));
+
 pub static CONFIG: Lazy<Mutex<Config>> = Lazy::new(|| Mutex::new(Config::default()));
+
+// built-in table merged with user entries, filled at startup
+pub static DEVICES: Lazy<Mutex<HashMap<u32, DeviceInfo>>> =
+    Lazy::new(|| Mutex::new(crate::data::devices::builtin()));
@@ -0,0 +1,405 @@
+use crate::common::command_queue::CommandError;
+use crate::common::status::{self, DeviceStatus};
+use crate::data::shared_vars::SESSIONS;
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::channel::{BusType, Channel, MatchingReceiver, Sender};
+use dbus::message::{MatchRule, SignalArgs};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::{
+    PropertiesPropertiesChanged, RequestNameReply,
+};
+use dbus::nonblock::SyncConnection;
+use dbus::MethodErr;
+use dbus_crossroads::{Crossroads, IfaceToken};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+
+pub const BUS_NAME: &str = "io.github.aplin";
+pub const INTERFACE: &str = "io.github.aplin.Device1";
+const ROOT_PATH: &str = "/io/github/aplin";
+
+// same scheme as bluez, /io/github/aplin/dev_AA_BB_CC_DD_EE_FF
+fn path(addr: bluer::Address) -> dbus::Path<'static> {
+    format!("{}/dev_{}", ROOT_PATH, addr.to_string().replace(':', "_")).into()
+}
+
+type Battery = HashMap<String, (u8, String)>;
+type Settings = HashMap<u8, u8>;
+
+fn battery(status: &DeviceStatus) -> Battery {
+    status
+        .battery
+        .iter()
+        .map(|(component, value)| (component.clone(), (value.level, value.state.clone())))
+        .collect()
+}
+
+fn settings(status: &DeviceStatus) -> Settings {
+    status.settings.clone().into_iter().collect()
+}
+
+fn with_status<R>(
+    addr: &bluer::Address,
+    f: impl FnOnce(&DeviceStatus) -> R,
+) -> Result<R, MethodErr> {
+    status::get(*addr)
+        .map(|status| f(&status))
+        .ok_or_else(|| MethodErr::no_path(&path(*addr)))
+}
+
+// properties differing between snapshots
+fn changed(old: &DeviceStatus, new: &DeviceStatus) -> PropMap {
+    let mut props = PropMap::new();
+    let mut set = |name: &str, differs: bool, value: Box<dyn RefArg>| {
+        if differs {
+            props.insert(name.to_string(), Variant(value));
+        }
+    };
+    set("Name", old.name != new.name, Box::new(new.name.clone()));
+    set("Model", old.model != new.model, Box::new(new.model.clone()));
+    set(
+        "ModelId",
+        old.model_id != new.model_id,
+        Box::new(new.model_id),
+    );
+    set(
+        "Connection",
+        old.connection != new.connection,
+        Box::new(new.connection.clone()),
+    );
+    set(
+        "Battery",
+        old.battery != new.battery,
+        Box::new(battery(new)),
+    );
+    set(
+        "ListeningMode",
+        old.listening_mode != new.listening_mode,
+        Box::new(new.listening_mode.clone()),
+    );
+    set("Ear", old.ear != new.ear, Box::new(new.ear.clone()));
+    set(
+        "AncCapable",
+        old.anc_capable != new.anc_capable,
+        Box::new(new.anc_capable),
+    );
+    set(
+        "AdaptiveCapable",
+        old.adaptive_capable != new.adaptive_capable,
+        Box::new(new.adaptive_capable),
+    );
+    set(
+        "Settings",
+        old.settings != new.settings,
+        Box::new(settings(new)),
+    );
+    props
+}
+
+// object data is device address, values are read from status snapshot
+fn register(cr: &mut Crossroads) -> IfaceToken<bluer::Address> {
+    cr.register(INTERFACE, |b| {
+        b.property("Address")
+            .emits_changed_const()
+            .get(|_, addr: &mut bluer::Address| Ok(addr.to_string()));
+        b.property("Name")
+            .get(|_, addr: &mut bluer::Address| with_status(addr, |status| status.name.clone()));
+        b.property("Model")
+            .get(|_, addr: &mut bluer::Address| with_status(addr, |status| status.model.clone()));
+        b.property("ModelId")
+            .get(|_, addr: &mut bluer::Address| with_status(addr, |status| status.model_id));
+        b.property("Connection")
+            .get(|_, addr: &mut bluer::Address| {
+                with_status(addr, |status| status.connection.clone())
+            });
+        b.property("Battery")
+            .get(|_, addr: &mut bluer::Address| with_status(addr, battery));
+        b.property("ListeningMode")
+            .get(|_, addr: &mut bluer::Address| {
+                with_status(addr, |status| status.listening_mode.clone())
+            });
+        b.property("Ear")
+            .get(|_, addr: &mut bluer::Address| with_status(addr, |status| status.ear.clone()));
+        b.property("AncCapable")
+            .get(|_, addr: &mut bluer::Address| with_status(addr, |status| status.anc_capable));
+        b.property("AdaptiveCapable")
+            .get(|_, addr: &mut bluer::Address| {
+                with_status(addr, |status| status.adaptive_capable)
+            });
+        b.property("Settings")
+            .get(|_, addr: &mut bluer::Address| with_status(addr, settings));
+
+        // replies once device confirmed new mode
+        b.method_with_cr_async(
+            "SetListeningMode",
+            ("mode",),
+            (),
+            |mut ctx, cr, (mode,): (String,)| {
+                let addr = cr.data_mut::<bluer::Address>(ctx.path()).copied();
+                async move {
+                    let result = match addr {
+                        Some(addr) => set_listening_mode(addr, &mode).await,
+                        None => Err(MethodErr::no_path(ctx.path())),
+                    };
+                    ctx.reply(result)
+                }
+            },
+        );
+        b.method(
+            "Disconnect",
+            (),
+            (),
+            |_, addr: &mut bluer::Address, ()| match SESSIONS.lock().unwrap().get(addr) {
+                Some(session) => {
+                    session.shutdown();
+                    Ok(())
+                }
+                None => Err(MethodErr::failed("Device is not connected")),
+            },
+        );
+    })
+}
+
+async fn set_listening_mode(addr: bluer::Address, mode: &str) -> Result<(), MethodErr> {
+    let anc = status::anc_from_id(mode).ok_or_else(|| {
+        MethodErr::invalid_arg(&format!(
+            "{}, expected off, noise-cancelling, transparency or adaptive",
+            mode
+        ))
+    })?;
+    crate::common::ab_device::set_anc(addr, anc)
+        .await
+        .map_err(|e| match e {
+            CommandError::NotSupported => (
+                "org.freedesktop.DBus.Error.NotSupported",
+                format!("Device does not support {}", mode),
+            )
+                .into(),
+            e => MethodErr::failed(&e),
+        })
+}
+
+// exports new objects, signals changes of existing ones and removes gone ones
+fn update(
+    cr: &Mutex<Crossroads>,
+    conn: &SyncConnection,
+    iface: IfaceToken<bluer::Address>,
+    exported: &mut HashMap<bluer::Address, DeviceStatus>,
+    addr: bluer::Address,
+    new: Option<DeviceStatus>,
+) {
+    let mut cr = cr.lock().unwrap();
+    match (exported.remove(&addr), new) {
+        (None, Some(new)) => {
+            cr.insert(path(addr), &[iface], addr);
+            exported.insert(addr, new);
+        }
+        (Some(old), Some(new)) => {
+            let props = changed(&old, &new);
+            if !props.is_empty() {
+                let signal = PropertiesPropertiesChanged {
+                    interface_name: INTERFACE.to_string(),
+                    changed_properties: props,
+                    invalidated_properties: vec![],
+                };
+                let _ = conn.send(signal.to_emit_message(&path(addr)));
+            }
+            exported.insert(addr, new);
+        }
+        (Some(_), None) => {
+            cr.remove::<bluer::Address>(&path(addr));
+        }
+        (None, None) => {}
+    }
+}
+
+pub async fn serve() -> Result<(), Box<dyn std::error::Error>> {
+    serve_on(Channel::get_private(BusType::Session)?).await
+}
+
+// owns bus name and exports object per connected device until bus connection is lost
+async fn serve_on(channel: Channel) -> Result<(), Box<dyn std::error::Error>> {
+    let (resource, conn) = dbus_tokio::connection::from_channel::<SyncConnection>(channel)?;
+    let (lost_tx, mut lost) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let _ = lost_tx.send(resource.await);
+    });
+    let reply = conn.request_name(BUS_NAME, false, true, true).await?;
+    if reply != RequestNameReply::PrimaryOwner {
+        return Err(format!("{} is already owned by another process", BUS_NAME).into());
+    }
+
+    let mut cr = Crossroads::new();
+    cr.set_async_support(Some((
+        conn.clone(),
+        Box::new(|x| {
+            tokio::spawn(x);
+        }),
+    )));
+    cr.set_object_manager_support(Some(conn.clone()));
+    let iface = register(&mut cr);
+    let object_manager = cr.object_manager::<()>();
+    cr.insert(ROOT_PATH, &[object_manager], ());
+    let cr = Arc::new(Mutex::new(cr));
+    conn.start_receive(MatchRule::new_method_call(), {
+        let cr = cr.clone();
+        Box::new(move |msg, conn| {
+            if cr.lock().unwrap().handle_message(msg, conn).is_err() {
+                log::debug!("Failed to handle D-Bus message");
+            }
+            true
+        })
+    });
+    log::debug!("D-Bus service {} started", BUS_NAME);
+
+    // subscribed before reading current state so no change is missed
+    let mut events = status::subscribe();
+    let mut exported = HashMap::new();
+    for (addr, status) in status::all() {
+        update(&cr, &conn, iface, &mut exported, addr, Some(status));
+    }
+    loop {
+        tokio::select! {
+            e = &mut lost => {
+                let e = e.map(|e| e.to_string()).unwrap_or_default();
+                return Err(format!("Lost connection to session bus: {}", e).into());
+            }
+            event = events.recv() => match event {
+                Ok((addr, status)) => update(&cr, &conn, iface, &mut exported, addr, status),
+                // too many changes at once, catch up with current state
+                Err(RecvError::Lagged(_)) => {
+                    let current: HashMap<_, _> = status::all().into_iter().collect();
+                    let gone: Vec<_> = exported
+                        .keys()
+                        .filter(|addr| !current.contains_key(addr))
+                        .copied()
+                        .collect();
+                    for addr in gone {
+                        update(&cr, &conn, iface, &mut exported, addr, None);
+                    }
+                    for (addr, status) in current {
+                        update(&cr, &conn, iface, &mut exported, addr, Some(status));
+                    }
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::nonblock::stdintf::org_freedesktop_dbus::{Introspectable, Properties};
+    use dbus::nonblock::Proxy;
+    use futures::StreamExt;
+    use std::io::BufRead;
+    use std::time::Duration;
+
+    // private bus, killed once dropped
+    struct Daemon(std::process::Child);
+
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn channel(address: &str) -> Channel {
+        let mut channel = Channel::open_private(address).unwrap();
+        channel.register().unwrap();
+        channel
+    }
+
+    #[tokio::test]
+    async fn exports_device_and_signals_changes() {
+        let Ok(child) = std::process::Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+        else {
+            eprintln!("dbus-daemon is not installed, skipping");
+            return;
+        };
+        let mut daemon = Daemon(child);
+        let mut address = String::new();
+        std::io::BufReader::new(daemon.0.stdout.as_mut().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim();
+
+        let addr: bluer::Address = "02:00:00:00:46:01".parse().unwrap();
+        status::publish(addr, DeviceStatus::sample(addr));
+        let service = channel(address);
+        tokio::spawn(async move {
+            if let Err(e) = serve_on(service).await {
+                log::error!("{}", e);
+            }
+        });
+
+        let (resource, conn) =
+            dbus_tokio::connection::from_channel::<SyncConnection>(channel(address)).unwrap();
+        tokio::spawn(resource);
+        let device = Proxy::new(BUS_NAME, path(addr), Duration::from_secs(2), conn.clone());
+        // service needs a moment to own its name
+        let mut model = None;
+        for _ in 0..50 {
+            if let Ok(value) = device.get::<String>(INTERFACE, "Model").await {
+                model = Some(value);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(model.as_deref(), Some("AirPods Pro 2"));
+        assert_eq!(
+            device.get::<String>(INTERFACE, "Address").await.unwrap(),
+            "02:00:00:00:46:01"
+        );
+        let battery: Battery = device.get(INTERFACE, "Battery").await.unwrap();
+        assert_eq!(battery["left"], (80, "discharging".to_string()));
+        let settings: Settings = device.get(INTERFACE, "Settings").await.unwrap();
+        assert_eq!(settings[&0x0d], 0x01);
+
+        // GetManagedObjects reply can't be read back by dbus crate, (ys) in variant
+        let root = Proxy::new(BUS_NAME, ROOT_PATH, Duration::from_secs(2), conn.clone());
+        let xml = root.introspect().await.unwrap();
+        assert!(xml.contains("org.freedesktop.DBus.ObjectManager"));
+        assert!(xml.contains("<node name=\"dev_02_00_00_00_46_01\"/>"));
+
+        let rule = PropertiesPropertiesChanged::match_rule(None, Some(&path(addr))).static_clone();
+        let (signal, mut changes) = conn
+            .add_match(rule)
+            .await
+            .unwrap()
+            .stream::<PropertiesPropertiesChanged>();
+        let mut new = DeviceStatus::sample(addr);
+        new.listening_mode = "noise-cancelling".to_string();
+        new.settings.insert(0x0d, 0x02);
+        status::publish(addr, new);
+        let (_, change) = tokio::time::timeout(Duration::from_secs(2), changes.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.interface_name, INTERFACE);
+        let mut names: Vec<_> = change.changed_properties.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, ["ListeningMode", "Settings"]);
+        assert_eq!(
+            change.changed_properties["ListeningMode"].0.as_str(),
+            Some("noise-cancelling")
+        );
+        conn.remove_match(signal.token()).await.unwrap();
+
+        status::remove(addr);
+        let mut gone = false;
+        for _ in 0..50 {
+            if device.get::<String>(INTERFACE, "Model").await.is_err() {
+                gone = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(gone);
+    }
+}
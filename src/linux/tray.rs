@@ -1,29 +1,33 @@
 use crate::common::{ab_battery::ABBatteryState, ab_device::ABDevice, ab_state::Anc};
+use crate::data::devices::DeviceIcon;
 
 impl ksni::Tray for ABDevice {
     fn id(&self) -> String {
-        env!("CARGO_PKG_NAME").into()
+        // unique per device, several trays can be shown at once
+        format!(
+            "{}-{}",
+            env!("CARGO_PKG_NAME"),
+            self.address.to_string().replace(':', "").to_lowercase()
+        )
     }
     fn icon_name(&self) -> String {
-        if self.is_monitors() {
-            "headphones-monitors".into()
-        } else {
-            "headphones-buds".into()
-        }
+        self.icon().name().into()
     }
     fn icon_pixmap(&self) -> Vec<ksni::Icon> {
         vec![to_icon(
             resvg::usvg::Options::default(),
             resvg::tiny_skia::Transform::identity(),
-            if self.is_monitors() {
-                include_str!("../../assets/icons/headphones-monitors.svg")
-            } else {
-                include_str!("../../assets/icons/headphones-buds.svg")
+            match self.icon() {
+                DeviceIcon::Buds => include_str!("../../assets/icons/headphones-buds.svg"),
+                DeviceIcon::Stemless => include_str!("../../assets/icons/headphones-stemless.svg"),
+                DeviceIcon::Earhook => include_str!("../../assets/icons/headphones-earhook.svg"),
+                DeviceIcon::Onear => include_str!("../../assets/icons/headphones-onear.svg"),
+                DeviceIcon::Monitors => include_str!("../../assets/icons/headphones-monitors.svg"),
             },
         )]
     }
     fn title(&self) -> String {
-        "APLin".into()
+        format!("APLin - {}", self.name())
     }
     fn menu(&self) -> Vec<ksni::MenuItem<Self>> {
         use ksni::menu::*;
@@ -49,40 +53,52 @@ impl ksni::Tray for ABDevice {
         }
         let mut tray_item = vec![
             StandardItem {
-                label: self.model.clone(),
+                label: self.name(),
                 enabled: false,
                 ..Default::default()
             }
             .into(),
             MenuItem::Separator,
-            RadioGroup {
-                selected: match &self.anc_state {
-                    Anc::Off => 0,
-                    Anc::NoiseCancelling => 1,
-                    Anc::Transparency => 2,
-                    Anc::Adaptive => 3,
-                },
-                select: Box::new(|this: &mut Self, option| {
-                    let anc = match option {
-                        0 => Anc::Off,
-                        1 => Anc::NoiseCancelling,
-                        2 => Anc::Transparency,
-                        3 => Anc::Adaptive,
-                        _ => {
-                            log::error!("Unknown ANC option selected: {}", option);
-                            Anc::Off
-                        }
-                    };
-                    log::debug!("Setting Anc to {:?}", anc);
-                    let self_to_move = this.clone();
-                    tokio::spawn(async move {
-                        self_to_move.send_anc(Some(anc)).await;
-                    });
-                }),
-                options: mode,
-            }
-            .into(),
         ];
+        // no ANC controls for devices seen only by advertisements
+        if self.anc_capable() && self.commands.is_some() {
+            tray_item.push(
+                RadioGroup {
+                    selected: match &self.anc_state {
+                        Anc::Off => 0,
+                        Anc::NoiseCancelling => 1,
+                        Anc::Transparency => 2,
+                        Anc::Adaptive => 3,
+                    },
+                    select: Box::new(|this: &mut Self, option| {
+                        let anc = match option {
+                            0 => Anc::Off,
+                            1 => Anc::NoiseCancelling,
+                            2 => Anc::Transparency,
+                            3 => Anc::Adaptive,
+                            _ => {
+                                log::error!("Unknown ANC option selected: {}", option);
+                                Anc::Off
+                            }
+                        };
+                        log::debug!("Setting Anc to {:?}", anc);
+                        let self_to_move = this.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = self_to_move.send_anc(Some(anc)).await {
+                                crate::common::commands::default_notification(format!(
+                                    "Failed to set {}: {}",
+                                    anc.get_name(),
+                                    e
+                                ))
+                                .await;
+                            }
+                        });
+                    }),
+                    options: mode,
+                }
+                .into(),
+            );
+        }
         if let Some((state, charge)) = self.battery_state.single {
             tray_item.push(
                 StandardItem {
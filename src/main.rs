@@ -1,7 +1,6 @@
 use clap::Parser;
-use futures::StreamExt;
 
-use crate::data::shared_vars::{AB_DEVICES, BBWATCHING, CONFIG};
+use crate::data::shared_vars::CONFIG;
 
 mod common;
 mod data;
@@ -19,14 +18,97 @@ struct Args {
     /// Path to the config file
     #[arg(short = 'c', long = "config")]
     config: Option<String>,
+
+    /// Connect to aplin-sim socket instead of bluetooth devices
+    #[arg(long = "sim", value_name = "SOCKET")]
+    sim: Option<std::path::PathBuf>,
+
+    /// Bluetooth adapter to use, can be repeated, overrides config
+    #[arg(long = "adapter", value_name = "NAME")]
+    adapter: Vec<String>,
+
+    /// Manage only this device (address, alias or model), overrides config
+    #[arg(long = "only", value_name = "DEVICE")]
+    only: Option<String>,
+
+    /// Record AAP traffic to pcapng file
+    #[arg(long = "capture", value_name = "FILE")]
+    capture: Option<std::path::PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-// dead code is here to suppress warning as we never read
-// the value, but need just fact of it's existence
-#[allow(dead_code)]
-enum MultiEvent {
-    Adapter(bluer::AdapterEvent),
-    Device(bluer::DeviceEvent),
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Replay pcapng capture through decoder, tray and notifications
+    Replay {
+        /// Path to the pcapng file
+        file: std::path::PathBuf,
+    },
+    /// Extract AAP frames from btsnoop or btmon log into pcapng corpus
+    ImportBtsnoop {
+        /// Path to the btsnoop log
+        input: std::path::PathBuf,
+
+        /// Path to the pcapng output, input with .pcapng extension by default
+        #[arg(short = 'o', long = "output")]
+        output: Option<std::path::PathBuf>,
+    },
+    /// Print annotated AAP frames of device and send hex frames from stdin
+    Raw {
+        /// Address or alias of the device, not needed with --sim
+        device: Option<String>,
+
+        /// Don't send handshake, notification and key requests after connecting
+        #[arg(long = "no-handshake")]
+        no_handshake: bool,
+    },
+    /// Show state of connected devices, asks running aplin
+    Status {
+        /// Address or alias of the device, all devices by default
+        device: Option<String>,
+    },
+    /// Show battery levels, asks running aplin
+    Battery {
+        /// Address or alias of the device, all devices by default
+        device: Option<String>,
+    },
+    /// Print state changes of running aplin as json, one object per line
+    Watch,
+    /// Print status line of running aplin for waybar, i3blocks or polybar on every change
+    Bar {
+        #[arg(long = "format", value_enum, default_value = "waybar")]
+        format: crate::common::bar::Format,
+
+        /// Text with placeholders like {left} {right} {case} {anc}, overrides config
+        #[arg(long = "template")]
+        template: Option<String>,
+
+        /// Address or alias of the device, all devices by default
+        device: Option<String>,
+    },
+    /// Change listening mode through running aplin
+    Anc {
+        #[command(subcommand)]
+        action: AncAction,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum AncAction {
+    /// Set listening mode: off, noise-cancelling, transparency or adaptive
+    Set {
+        mode: String,
+
+        /// Address or alias of the device, needed when several are connected
+        device: Option<String>,
+    },
+    /// Switch to next listening mode device supports
+    Cycle {
+        /// Address or alias of the device, needed when several are connected
+        device: Option<String>,
+    },
 }
 
 #[tokio::main]
@@ -41,124 +123,169 @@ async fn main() {
     env_logger::init();
     log::debug!("Logger initialized");
 
-    let session = match bluer::Session::new().await {
-        Ok(session) => session,
-        Err(e) => {
-            log::error!("Failed to create session: {}", e);
-            std::process::exit(1);
-        }
-    };
-    let mut all_events = vec![];
-
     let new_config = crate::data::config::Config::load(args.config.map(std::path::PathBuf::from));
     {
+        crate::data::devices::load(&new_config.devices);
         let mut config = CONFIG.lock().unwrap();
         *config = new_config;
+        if !args.adapter.is_empty() {
+            config.adapters = args.adapter.clone();
+        }
+        if args.only.is_some() {
+            config.only = args.only.clone();
+        }
+    }
+
+    if let Some(path) = &args.capture {
+        if let Err(e) = crate::common::capture::start(path) {
+            log::error!("Failed to start capture to {:#?}: {}", path, e);
+            std::process::exit(1);
+        }
     }
 
-    loop {
-        log::debug!("Starting device scan");
-        let adapter = match session.default_adapter().await {
-            Ok(adapter) => adapter,
-            Err(e) => {
-                log::error!("Failed to get default adapter: {}", e);
+    match &args.command {
+        Some(Command::Replay { file }) => {
+            if let Err(e) = crate::common::capture::replay(file).await {
+                log::error!("Failed to replay {:#?}: {}", file, e);
                 std::process::exit(1);
             }
-        };
-        let adapter_events: std::pin::Pin<Box<dyn futures::Stream<Item = MultiEvent>>> =
-            Box::pin(adapter.events().await.unwrap().map(MultiEvent::Adapter));
-        all_events.push(adapter_events);
-        for addr in adapter.device_addresses().await.unwrap() {
-            if let Some(true) = BBWATCHING.lock().await.get(&addr) {
-                log::debug!("Device {} is already being watched", addr);
-                continue;
-            }
-            let device = adapter.device(addr).unwrap();
-            let modalias = match device.modalias().await {
-                Ok(modalias) => {
-                    if let Some(inner_modalias) = modalias {
-                        if inner_modalias.vendor != 76
-                            || !AB_DEVICES.contains(&inner_modalias.product)
-                        {
-                            log::debug!("Device {} is not an Apple device", addr);
-                            continue;
-                        }
-                        inner_modalias
-                    } else {
-                        log::warn!("Modalias is empty, skipping device: {}", addr);
-                        continue;
-                    }
-                }
+            return;
+        }
+        Some(Command::ImportBtsnoop { input, output }) => {
+            let output = output
+                .clone()
+                .unwrap_or_else(|| input.with_extension("pcapng"));
+            match crate::common::btsnoop::import(input, &output) {
+                Ok(frames) => println!("Wrote {} AAP frames to {}", frames, output.display()),
                 Err(e) => {
-                    log::error!("Failed to get modalias for device {}: {}", addr, e);
-                    continue;
+                    log::error!("Failed to import {:#?}: {}", input, e);
+                    std::process::exit(1);
                 }
+            }
+            return;
+        }
+        Some(Command::Raw {
+            device,
+            no_handshake,
+        }) => {
+            let address = match device {
+                Some(device) => match CONFIG.lock().unwrap().resolve_device(device) {
+                    Some(address) => Some(address),
+                    None => {
+                        log::error!("Unknown device {:?}", device);
+                        std::process::exit(1);
+                    }
+                },
+                None => None,
             };
-            log::debug!("Device {} has modalias:", addr);
-            log::debug!(
-                "Device name: {:?}",
-                device.name().await.expect("Unknown").unwrap()
-            );
-            log::debug!(
-                "modalias: \n source: {} \n vendor: {} \n product: {} \n device: {}",
-                modalias.source,
-                modalias.vendor,
-                modalias.product,
-                modalias.device
-            );
-            log::debug!("Device {} is an Apple device", addr);
-            if let std::collections::hash_map::Entry::Vacant(e) =
-                BBWATCHING.lock().await.entry(addr)
+            if let Err(e) =
+                crate::common::raw::console(address, args.sim.as_deref(), !no_handshake).await
             {
-                match device.events().await {
-                    Ok(events) => {
-                        e.insert(false);
-                        all_events.push(Box::pin(events.map(MultiEvent::Device)));
-                    }
-                    Err(e) => {
-                        log::error!(
-                            "Failed to get events for device {}: {} \n   Device won't be monitored",
-                            addr,
-                            e
-                        );
-                        continue;
-                    }
-                }
+                log::error!("Raw console failed: {}", e);
+                std::process::exit(1);
             }
-            match device.is_connected().await {
-                Ok(connected) if connected => {
-                    BBWATCHING.lock().await.insert(addr, true);
-                    let adapter = adapter.clone();
-                    let device_c = device.clone();
-                    tokio::task::spawn(async move {
-                        let mut ab_device = crate::common::ab_device::ABDevice::new();
-                        ab_device.model = device.name().await.expect("Unknown").unwrap();
-                        ab_device.model_id = modalias.product;
-                        crate::common::ab_device::ABDevice::monitor(
-                            &mut ab_device,
-                            device,
-                            adapter,
-                        )
-                        .await
-                        .unwrap();
-                        log::debug!("Device closed {} ", addr);
-                        BBWATCHING.lock().await.insert(addr, false);
-                        device_c.disconnect().await.unwrap_or_else(|e| {
-                            log::error!("Failed to disconnect device {}: {}", addr, e);
-                        });
-                    });
-                }
-                Err(e) => {
-                    log::error!("Failed to get connection status for device {}: {}", addr, e);
-                }
-                _ => {}
+            return;
+        }
+        Some(Command::Status { device }) => {
+            if let Err(e) = crate::common::cli::status(device.clone()).await {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Battery { device }) => {
+            if let Err(e) = crate::common::cli::battery_levels(device.clone()).await {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Watch) => {
+            if let Err(e) = crate::common::ipc::watch_stream().await {
+                log::error!("{}", e);
+                std::process::exit(1);
             }
+            return;
         }
-        log::debug!("Waiting for events");
-        let _ = futures::stream::select_all(&mut all_events)
-            .next()
-            .await
-            .is_some();
-        log::debug!("Some event received, updating device list");
+        Some(Command::Bar {
+            format,
+            template,
+            device,
+        }) => {
+            let template = template
+                .clone()
+                .unwrap_or_else(|| CONFIG.lock().unwrap().bar_template.clone());
+            crate::common::bar::run(*format, &template, device.clone()).await;
+            return;
+        }
+        Some(Command::Anc { action }) => {
+            let request = match action {
+                AncAction::Set { mode, device } => crate::common::ipc::Request::SetAnc {
+                    device: device.clone(),
+                    mode: mode.clone(),
+                },
+                AncAction::Cycle { device } => crate::common::ipc::Request::CycleAnc {
+                    device: device.clone(),
+                },
+            };
+            if let Err(e) = crate::common::cli::anc(request).await {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+
+    tokio::spawn(async {
+        if let Err(e) = crate::common::ipc::serve().await {
+            log::error!("Control socket stopped: {}", e);
+        }
+    });
+    if CONFIG.lock().unwrap().state_files {
+        tokio::spawn(async {
+            if let Err(e) = crate::common::state_file::run().await {
+                log::error!("State files stopped: {}", e);
+            }
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    if CONFIG.lock().unwrap().dbus {
+        tokio::spawn(async {
+            if let Err(e) = crate::linux::dbus::serve().await {
+                log::error!("D-Bus service stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(path) = args.sim {
+        // model is taken from metadata simulator sends
+        let mut ab_device = crate::common::ab_device::ABDevice::new();
+        ab_device.model = "Simulator".to_string();
+        if let Err(e) = ab_device.simulate(&path).await {
+            log::error!("Failed to run simulated device: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let session = match bluer::Session::new().await {
+        Ok(session) => session,
+        Err(e) => {
+            log::error!("Failed to create session: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let proximity = {
+        let config = CONFIG.lock().unwrap();
+        config.proximity
+            || config.auto_connect.enabled
+            || config.auto_connect.devices.values().any(|enabled| *enabled)
+    };
+    if let Err(e) = crate::common::device_manager::watch_adapters(session, proximity).await {
+        log::error!("Failed to watch adapters: {}", e);
+        std::process::exit(1);
     }
 }
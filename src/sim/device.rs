@@ -0,0 +1,168 @@
+use crate::scenario::{Anc, Change, Ear};
+
+#[derive(Debug, Clone, Default)]
+pub struct SimDevice {
+    // (level, charging)
+    pub single: Option<(u8, bool)>,
+    pub left: Option<(u8, bool)>,
+    pub right: Option<(u8, bool)>,
+    pub case: Option<(u8, bool)>,
+    pub left_ear: Option<Ear>,
+    pub right_ear: Option<Ear>,
+    pub anc: Option<Anc>,
+    // model number like A2698
+    pub model: String,
+}
+
+// which packets have to be sent after change
+#[derive(Debug, Default)]
+pub struct Changed {
+    pub battery: bool,
+    pub ear: bool,
+    pub anc: bool,
+}
+
+fn apply_battery(
+    battery: &mut Option<(u8, bool)>,
+    level: Option<u8>,
+    charging: Option<bool>,
+) -> bool {
+    if level.is_none() && charging.is_none() {
+        return false;
+    }
+    let (old_level, old_charging) = battery.unwrap_or((100, false));
+    *battery = Some((
+        level.unwrap_or(old_level).min(100),
+        charging.unwrap_or(old_charging),
+    ));
+    true
+}
+
+impl SimDevice {
+    pub fn apply(&mut self, change: &Change) -> Changed {
+        let mut changed = Changed::default();
+        changed.battery |= apply_battery(&mut self.single, change.single, change.single_charging);
+        changed.battery |= apply_battery(&mut self.left, change.left, change.left_charging);
+        changed.battery |= apply_battery(&mut self.right, change.right, change.right_charging);
+        changed.battery |= apply_battery(&mut self.case, change.case, change.case_charging);
+        if let Some(ear) = change.left_ear {
+            self.left_ear = Some(ear);
+            changed.ear = true;
+        }
+        if let Some(ear) = change.right_ear {
+            self.right_ear = Some(ear);
+            changed.ear = true;
+        }
+        if let Some(anc) = change.anc {
+            self.anc = Some(anc);
+            changed.anc = true;
+        }
+        changed
+    }
+
+    // 0x04: count, then type, 0x01, level, status, 0x01 for every component
+    pub fn battery_packet(&self) -> Vec<u8> {
+        let components = [
+            (0x01, self.single),
+            (0x02, self.right),
+            (0x04, self.left),
+            (0x08, self.case),
+        ];
+        let mut packet = vec![0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x00];
+        for (component, battery) in components {
+            if let Some((level, charging)) = battery {
+                packet[6] += 1;
+                packet.extend([
+                    component,
+                    0x01,
+                    level,
+                    if charging { 0x01 } else { 0x02 },
+                    0x01,
+                ]);
+            }
+        }
+        packet
+    }
+
+    // 0x06: left and right ear state, 0x00 in ear, 0x01 out of ear, 0x02 in case
+    pub fn ear_packet(&self) -> Vec<u8> {
+        let ear_byte = |ear: Option<Ear>| match ear {
+            Some(Ear::In) => 0x00,
+            Some(Ear::Out) | None => 0x01,
+            Some(Ear::Case) => 0x02,
+        };
+        vec![
+            0x04,
+            0x00,
+            0x04,
+            0x00,
+            0x06,
+            0x00,
+            ear_byte(self.left_ear),
+            ear_byte(self.right_ear),
+        ]
+    }
+
+    // 0x1d: name, model number and manufacturer, each null terminated
+    pub fn metadata_packet(&self) -> Vec<u8> {
+        let mut packet = vec![0x04, 0x00, 0x04, 0x00, 0x1d, 0x00];
+        for field in ["aplin-sim", &self.model, "Apple Inc."] {
+            packet.extend(field.as_bytes());
+            packet.push(0x00);
+        }
+        packet
+    }
+
+    // 0x09 0x0d: listening mode setting
+    pub fn anc_packet(&self) -> Vec<u8> {
+        let anc_byte = match self.anc {
+            Some(Anc::Off) | None => 0x01,
+            Some(Anc::NoiseCancelling) => 0x02,
+            Some(Anc::Transparency) => 0x03,
+            Some(Anc::Adaptive) => 0x04,
+        };
+        vec![
+            0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0d, anc_byte, 0x00, 0x00, 0x00,
+        ]
+    }
+
+    pub fn packets(&self, changed: &Changed) -> Vec<Vec<u8>> {
+        let mut packets = vec![];
+        if changed.battery {
+            packets.push(self.battery_packet());
+        }
+        if changed.ear {
+            packets.push(self.ear_packet());
+        }
+        if changed.anc {
+            packets.push(self.anc_packet());
+        }
+        packets
+    }
+
+    pub fn anc_from_byte(anc_byte: u8) -> Option<Anc> {
+        match anc_byte {
+            0x01 => Some(Anc::Off),
+            0x02 => Some(Anc::NoiseCancelling),
+            0x03 => Some(Anc::Transparency),
+            0x04 => Some(Anc::Adaptive),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_has_model_number() {
+        let device = SimDevice {
+            model: "A2698".to_string(),
+            ..Default::default()
+        };
+        let packet = device.metadata_packet();
+        assert_eq!(packet[4], 0x1d);
+        assert_eq!(&packet[6..], b"aplin-sim\0A2698\0Apple Inc.\0");
+    }
+}
@@ -0,0 +1,184 @@
+use clap::Parser;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::device::SimDevice;
+use crate::scenario::Scenario;
+
+mod device;
+mod scenario;
+
+#[derive(Parser)]
+#[command(name = "aplin-sim")]
+#[command(version, about="Simulated airpods for aplin development", long_about = None)]
+struct Args {
+    /// Enable Debug Mode
+    #[arg(short = 'd', long = "debug")]
+    debug: bool,
+
+    /// Path to the scenario file
+    #[arg(short = 's', long = "scenario")]
+    scenario: Option<PathBuf>,
+
+    /// Model number reported in metadata, like A2698, overrides scenario
+    #[arg(short = 'm', long = "model")]
+    model: Option<String>,
+
+    /// Path to the socket to listen on
+    #[arg(long = "socket")]
+    socket: Option<PathBuf>,
+}
+
+pub fn default_socket() -> PathBuf {
+    let runtime = std::env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| "/tmp".to_string());
+    PathBuf::from(runtime).join("aplin-sim.sock")
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    std::env::set_var("RUST_LOG", "info");
+    if args.debug {
+        std::env::set_var("RUST_LOG", "debug");
+    }
+
+    env_logger::init();
+
+    let mut scenario = match Scenario::load(args.scenario.as_deref()) {
+        Ok(scenario) => scenario,
+        Err(e) => {
+            log::error!("Failed to load scenario: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(model) = args.model {
+        scenario.model = model;
+    }
+
+    let socket = args.socket.unwrap_or_else(default_socket);
+    let _ = std::fs::remove_file(&socket);
+    let listener = match tokio::net::UnixListener::bind(&socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to bind {:#?}: {}", socket, e);
+            std::process::exit(1);
+        }
+    };
+    log::info!("Listening on {:#?}", socket);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                log::info!("Host connected");
+                serve(stream, &scenario).await;
+                log::info!("Host disconnected");
+            }
+            Err(e) => log::error!("Failed to accept connection: {}", e),
+        }
+    }
+}
+
+// packets are prefixed with u16 le length, same as aplin UnixTransport
+async fn send(writer: &mut tokio::net::unix::OwnedWriteHalf, packet: &[u8]) -> std::io::Result<()> {
+    log::debug!("Sending {:02x?}", packet);
+    writer
+        .write_all(&(packet.len() as u16).to_le_bytes())
+        .await?;
+    writer.write_all(packet).await
+}
+
+async fn serve(stream: tokio::net::UnixStream, scenario: &Scenario) {
+    let (mut reader, mut writer) = stream.into_split();
+
+    // reading in separate task as read_exact is not cancel safe
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let mut len = [0u8; 2];
+            if reader.read_exact(&mut len).await.is_err() {
+                break;
+            }
+            let mut packet = vec![0u8; u16::from_le_bytes(len).into()];
+            if reader.read_exact(&mut packet).await.is_err() || tx.send(packet).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut device = SimDevice {
+        model: scenario.model.clone(),
+        ..Default::default()
+    };
+    device.apply(&scenario.initial);
+    let mut events = scenario.events.iter().peekable();
+    // scenario starts once host requests notifications
+    let mut started: Option<tokio::time::Instant> = None;
+
+    loop {
+        let next_event = match (started, events.peek()) {
+            (Some(started), Some(step)) => started + std::time::Duration::from_secs_f64(step.at),
+            _ => tokio::time::Instant::now() + std::time::Duration::from_secs(3600),
+        };
+        let packets = tokio::select! {
+            packet = rx.recv() => {
+                let Some(packet) = packet else {
+                    return;
+                };
+                log::debug!("Received {:02x?}", packet);
+                match packet.get(4) {
+                    _ if packet.starts_with(&[0x00, 0x00, 0x04, 0x00, 0x01, 0x00]) => {
+                        log::info!("Handshake");
+                        vec![vec![0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00]]
+                    }
+                    Some(0x0f) => {
+                        log::info!("Notifications requested, starting scenario");
+                        started.get_or_insert_with(tokio::time::Instant::now);
+                        vec![
+                            device.metadata_packet(),
+                            device.battery_packet(),
+                            device.ear_packet(),
+                            device.anc_packet(),
+                        ]
+                    }
+                    Some(0x09) if packet.get(6) == Some(&0x0d) => {
+                        match packet.get(7).copied().and_then(SimDevice::anc_from_byte) {
+                            Some(anc) => {
+                                log::info!("Anc set to {:?}", anc);
+                                device.anc = Some(anc);
+                                vec![device.anc_packet()]
+                            }
+                            None => {
+                                log::warn!("Unknown anc value {:02x?}", packet.get(7));
+                                vec![]
+                            }
+                        }
+                    }
+                    _ => {
+                        log::debug!("Ignoring packet");
+                        vec![]
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(next_event) => {
+                let Some(step) = events.next() else {
+                    continue;
+                };
+                log::info!("t={}s {:?}", step.at, step.change);
+                if step.change.disconnect {
+                    return;
+                }
+                let changed = device.apply(&step.change);
+                device.packets(&changed)
+            }
+        };
+        for packet in packets {
+            if let Err(e) = send(&mut writer, &packet).await {
+                log::error!("Failed to send packet: {}", e);
+                return;
+            }
+        }
+    }
+}
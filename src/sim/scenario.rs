@@ -0,0 +1,118 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ear {
+    In,
+    Out,
+    Case,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Anc {
+    Off,
+    NoiseCancelling,
+    Transparency,
+    Adaptive,
+}
+
+// every field is optional, only set values are changed
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Change {
+    pub single: Option<u8>,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+    pub case: Option<u8>,
+    pub single_charging: Option<bool>,
+    pub left_charging: Option<bool>,
+    pub right_charging: Option<bool>,
+    pub case_charging: Option<bool>,
+    pub left_ear: Option<Ear>,
+    pub right_ear: Option<Ear>,
+    pub anc: Option<Anc>,
+    pub disconnect: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Step {
+    // seconds since notifications were requested
+    pub at: f64,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Scenario {
+    // model number sent in metadata, AirPods Pro 2 by default
+    pub model: String,
+    pub initial: Change,
+    pub events: Vec<Step>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            model: "A2698".to_string(),
+            initial: Change {
+                left: Some(100),
+                right: Some(100),
+                case: Some(100),
+                left_ear: Some(Ear::In),
+                right_ear: Some(Ear::In),
+                anc: Some(Anc::Off),
+                ..Default::default()
+            },
+            events: vec![],
+        }
+    }
+}
+
+impl Scenario {
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut scenario: Scenario = serde_yml::from_str(text)?;
+        // at is turned into sleep duration, which can't be infinite or negative
+        for step in &scenario.events {
+            if std::time::Duration::try_from_secs_f64(step.at).is_err() {
+                return Err(format!("Invalid time of event: at: {}", step.at).into());
+            }
+        }
+        scenario.events.sort_by(|a, b| a.at.total_cmp(&b.at));
+        Ok(scenario)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_events() {
+        let scenario = Scenario::parse(
+            "events:\n  - at: 2\n    left: 50\n  - at: 0.5\n    anc: transparency\n",
+        )
+        .unwrap();
+        assert_eq!(scenario.events[0].at, 0.5);
+        assert_eq!(scenario.events[0].change.anc, Some(Anc::Transparency));
+        assert_eq!(scenario.events[1].change.left, Some(50));
+    }
+
+    #[test]
+    fn rejects_invalid_time() {
+        for at in [".inf", "-.inf", ".nan", "-1", "1e300"] {
+            let text = format!("events:\n  - at: {}\n    left: 50\n", at);
+            assert!(Scenario::parse(&text).is_err(), "at: {} accepted", at);
+        }
+    }
+}